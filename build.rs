@@ -15,6 +15,11 @@ fn main() {
     );
 
     println!("cargo:rustc-env=BUILD_ID={}", build_id);
+    // The clock can't be earlier than this, see TimeSource
+    println!(
+        "cargo:rustc-env=BUILD_UNIX_TIME={}",
+        chrono::Utc::now().timestamp()
+    );
 
     let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
     let mut out_dir2 = out_dir.as_path();
@@ -0,0 +1,275 @@
+//! Replays a scripted day of MQTT inputs through the lighting policy.
+//!
+//! Runs on the host:
+//!
+//! ```text
+//! cargo +stable run --no-default-features --features simulator --bin simulator -- timeline.txt
+//! ```
+//!
+//! The timeline is a text file with one retained MQTT value per line, applied at the given
+//! local time of the simulated day:
+//!
+//! ```text
+//! # time    topic                     json value
+//! 06:00:00  alarm/state               {"next_alarm":"2024-03-01T06:30:00Z","enabled":true}
+//! 07:31:00  alarm/phone/is_playing    false
+//! 07:31:00  alarm/last_played         {"last_played_time":"2024-03-01T06:31:00Z"}
+//! 22:00:00  alarm/phone/is_user_in_bed true
+//! ```
+//!
+//! The resulting target color for every simulated second is written as CSV.
+use std::io::Write;
+
+use bedroom_lights3::clock::TimeQuality;
+use bedroom_lights3::color::SceneLight;
+use bedroom_lights3::manual::DEFAULT_ALARM_PRIORITY;
+use bedroom_lights3::scene::{
+    AlarmLastPlayed, InnerAlarmState, OverrideEvent, SceneColors, SceneEngine, SceneInputs,
+};
+use bedroom_lights3::sunrise::SunriseCurve;
+use bedroom_lights3::timezone::{Timezone, DEFAULT_TIMEZONE};
+use chrono::{Duration, NaiveDate, NaiveTime};
+
+const USAGE: &str =
+    "Usage: simulator <timeline> [--date YYYY-MM-DD] [--tz TIMEZONE] [--step SECONDS] [--out FILE]";
+
+struct Event {
+    time: NaiveTime,
+    topic: String,
+    value: serde_json::Value,
+}
+
+struct Args {
+    timeline: String,
+    date: NaiveDate,
+    tz: Timezone,
+    step: i64,
+    out: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut timeline = None;
+    let mut date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    let mut tz = Timezone::from_config(DEFAULT_TIMEZONE).unwrap();
+    let mut step = 1;
+    let mut out = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("Missing value for {name}"));
+        match arg.as_str() {
+            "--date" => {
+                date = value("--date")?
+                    .parse()
+                    .map_err(|e| format!("Invalid date: {e}"))?;
+            }
+            "--tz" => {
+                tz = Timezone::from_config(&value("--tz")?).map_err(|e| e.to_string())?;
+            }
+            "--step" => {
+                step = value("--step")?
+                    .parse()
+                    .map_err(|e| format!("Invalid step: {e}"))?;
+                if step <= 0 {
+                    return Err("Step must be positive".to_string());
+                }
+            }
+            "--out" => out = Some(value("--out")?),
+            _ if timeline.is_none() && !arg.starts_with("--") => timeline = Some(arg),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+
+    Ok(Args {
+        timeline: timeline.ok_or("Missing timeline file")?,
+        date,
+        tz,
+        step,
+        out,
+    })
+}
+
+fn parse_timeline(text: &str) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = |msg: String| format!("Line {}: {msg}", line_index + 1);
+        let Some((time, topic, value)) =
+            line.split_once(char::is_whitespace)
+                .and_then(|(time, rest)| {
+                    let (topic, value) = rest.trim_start().split_once(char::is_whitespace)?;
+                    Some((time, topic, value))
+                })
+        else {
+            return Err(err("Expected <time> <topic> <json value>".to_string()));
+        };
+        events.push(Event {
+            time: NaiveTime::parse_from_str(time, "%H:%M:%S")
+                .map_err(|e| err(format!("Invalid time {time}: {e}")))?,
+            topic: topic.to_string(),
+            value: serde_json::from_str(value.trim())
+                .map_err(|e| err(format!("Invalid value for {topic}: {e}")))?,
+        });
+    }
+    events.sort_by_key(|e| e.time);
+    Ok(events)
+}
+
+/// Matches an MQTT topic filter with single level `+` wildcards
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (None, None) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            _ => return false,
+        }
+    }
+}
+
+fn apply_event(
+    inputs: &mut SceneInputs,
+    engine: &mut SceneEngine,
+    event: &Event,
+) -> Result<(), String> {
+    fn parse<T: serde::de::DeserializeOwned>(event: &Event) -> Result<T, String> {
+        serde_json::from_value(event.value.clone())
+            .map_err(|e| format!("Invalid value for {}: {e}", event.topic))
+    }
+
+    let topic = event.topic.as_str();
+    if topic_matches("alarm/state", topic) {
+        inputs.alarm_state = parse(event)?;
+    } else if topic_matches("alarm/+/is_playing", topic) {
+        inputs.is_playing = parse(event)?;
+    } else if topic_matches("alarm/+/is_user_in_bed", topic) {
+        inputs.is_user_in_bed = parse(event)?;
+    } else if topic_matches("alarm/last_played", topic) {
+        inputs.alarm_last_played = parse(event)?;
+    } else if topic_matches("lights/schedule", topic) {
+        inputs.schedule = parse(event)?;
+    } else if topic_matches("lights/animations/sunrise", topic) {
+        let curve: Option<SunriseCurve> = parse(event)?;
+        let curve = curve.unwrap_or_default();
+        curve
+            .validate()
+            .map_err(|e| format!("Invalid sunrise curve: {e}"))?;
+        engine.set_sunrise(curve);
+    } else if topic_matches("lights/+/override", topic) {
+        inputs.manual_override = parse(event)?;
+    } else if topic_matches("lights/config/alarm_priority", topic) {
+        inputs.alarm_priority = parse(event)?;
+    } else if let Some(scene) = topic.strip_prefix("lights/colors/") {
+        let color: SceneLight = parse(event)?;
+        match scene {
+            "plant" => inputs.colors.plant = color,
+            "evening" => inputs.colors.evening = color,
+            "in_bed" => inputs.colors.in_bed = color,
+            "snooze" => inputs.colors.snooze = color,
+            _ => return Err(format!("Unknown scene color {topic}")),
+        }
+    } else {
+        return Err(format!("Unsupported topic {topic}"));
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let text = std::fs::read_to_string(&args.timeline)
+        .map_err(|e| format!("Failed to read {}: {e}", args.timeline))?;
+    let events = parse_timeline(&text)?;
+
+    let mut out: Box<dyn Write> = match &args.out {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).map_err(|e| format!("Failed to create {path}: {e}"))?,
+        )),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+    };
+
+    // Find the UTC time of local midnight. The second iteration corrects for a
+    // daylight saving transition between UTC midnight and local midnight.
+    let midnight = args.date.and_time(NaiveTime::MIN).and_utc();
+    let mut start = midnight;
+    for _ in 0..2 {
+        start = midnight - Duration::seconds(args.tz.offset_at(start).local_minus_utc() as i64);
+    }
+
+    let mut inputs = SceneInputs {
+        now: args.tz.to_local(start),
+        time_quality: TimeQuality::Synced,
+        alarm_state: InnerAlarmState {
+            next_alarm: Default::default(),
+            enabled: false,
+        },
+        alarm_last_played: AlarmLastPlayed {
+            last_played_time: None,
+        },
+        is_playing: false,
+        is_user_in_bed: false,
+        colors: SceneColors::default(),
+        schedule: Default::default(),
+        manual_override: None,
+        alarm_priority: DEFAULT_ALARM_PRIORITY,
+    };
+    let mut engine = SceneEngine::new();
+    let mut next_event = events.iter().peekable();
+
+    let write_err = |e: std::io::Error| format!("Failed to write output: {e}");
+    writeln!(out, "time,r,g,b,w,reason,event").map_err(write_err)?;
+
+    let mut seconds = 0;
+    // Days with daylight saving transitions are 23 or 25 hours long
+    loop {
+        inputs.now = args.tz.to_local(start + Duration::seconds(seconds));
+        if inputs.now.date_naive() != args.date {
+            break;
+        }
+        while let Some(event) = next_event.next_if(|e| e.time <= inputs.now.time()) {
+            apply_event(&mut inputs, &mut engine, event)?;
+        }
+
+        let output = engine.update(&inputs);
+        // Like the firmware, clear expired overrides
+        if output.override_event == Some(OverrideEvent::Expired) {
+            inputs.manual_override = None;
+        }
+        let events: Vec<String> = output
+            .event
+            .map(|e| format!("{e:?}"))
+            .into_iter()
+            .chain(output.override_event.map(|e| format!("Override{e:?}")))
+            .collect();
+        writeln!(
+            out,
+            "{},{:.2},{:.2},{:.2},{:.2},{:?},{}",
+            inputs.now.format("%H:%M:%S"),
+            output.color[0],
+            output.color[1],
+            output.color[2],
+            output.color[3],
+            output.reason,
+            events.join(";"),
+        )
+        .map_err(write_err)?;
+
+        seconds += args.step;
+    }
+
+    out.flush().map_err(write_err)
+}
+
+fn main() {
+    let result = parse_args()
+        .map_err(|e| format!("{e}\n{USAGE}"))
+        .and_then(run);
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
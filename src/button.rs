@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use crate::color::RGBWColor;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Gesture {
+    Short,
+    Long,
+    Double,
+}
+
+/// What a gesture does.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonAction {
+    /// Turns the light on if it is off and off if it is on, until the scene changes
+    ToggleLight,
+    /// Pauses the wakeup light for the snooze time
+    SnoozeSunrise,
+    /// Stops the wakeup light until the next alarm
+    DismissSunrise,
+    /// Steps through the scene colors, until the scene changes
+    CycleScenes,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct ButtonConfig {
+    pub short: Option<ButtonAction>,
+    pub long: Option<ButtonAction>,
+    pub double: Option<ButtonAction>,
+    /// Color of [`ButtonAction::ToggleLight`] when turning the light on
+    #[serde(default = "default_on_color")]
+    pub on_color: RGBWColor,
+    #[serde(default = "default_snooze_minutes")]
+    pub snooze_minutes: u32,
+}
+
+fn default_on_color() -> RGBWColor {
+    "2700K".parse().unwrap()
+}
+
+fn default_snooze_minutes() -> u32 {
+    9
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        Self {
+            short: Some(ButtonAction::ToggleLight),
+            long: Some(ButtonAction::DismissSunrise),
+            double: Some(ButtonAction::CycleScenes),
+            on_color: default_on_color(),
+            snooze_minutes: default_snooze_minutes(),
+        }
+    }
+}
+
+impl ButtonConfig {
+    pub fn action(&self, gesture: Gesture) -> Option<ButtonAction> {
+        match gesture {
+            Gesture::Short => self.short,
+            Gesture::Long => self.long,
+            Gesture::Double => self.double,
+        }
+    }
+}
+
+/// Turns a bouncy button level sampled at a fixed rate into gestures.
+///
+/// A short press is only reported once the time for a second press has passed, so it is never
+/// reported as part of a double press. Long presses are reported while the button is still held.
+#[derive(Debug, Clone)]
+pub struct GestureDetector {
+    debounce: Duration,
+    long_press: Duration,
+    double_press_gap: Duration,
+    raw: bool,
+    raw_since: Duration,
+    pressed: bool,
+    press_start: Duration,
+    /// Release time of a short press that may become a double press
+    pending_short: Option<Duration>,
+    second_press: bool,
+    long_reported: bool,
+}
+
+impl Default for GestureDetector {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_millis(30),
+            Duration::from_millis(700),
+            Duration::from_millis(300),
+        )
+    }
+}
+
+impl GestureDetector {
+    pub fn new(debounce: Duration, long_press: Duration, double_press_gap: Duration) -> Self {
+        Self {
+            debounce,
+            long_press,
+            double_press_gap,
+            raw: false,
+            raw_since: Duration::ZERO,
+            pressed: false,
+            press_start: Duration::ZERO,
+            pending_short: None,
+            second_press: false,
+            long_reported: false,
+        }
+    }
+
+    /// Feeds the button level at time `now`, measured from any fixed point.
+    pub fn update(&mut self, pressed: bool, now: Duration) -> Option<Gesture> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since = now;
+        }
+        let stable = now.saturating_sub(self.raw_since) >= self.debounce;
+
+        if stable && self.raw != self.pressed {
+            self.pressed = self.raw;
+            if self.pressed {
+                self.press_start = now;
+                self.long_reported = false;
+                self.second_press = self.pending_short.take().is_some();
+            } else if self.long_reported {
+                // Already reported
+            } else if self.second_press {
+                self.second_press = false;
+                return Some(Gesture::Double);
+            } else {
+                self.pending_short = Some(now);
+            }
+            return None;
+        }
+
+        if self.pressed
+            && !self.long_reported
+            && !self.second_press
+            && now.saturating_sub(self.press_start) >= self.long_press
+        {
+            self.long_reported = true;
+            return Some(Gesture::Long);
+        }
+
+        if let Some(released) = self.pending_short {
+            if !self.pressed && now.saturating_sub(released) >= self.double_press_gap {
+                self.pending_short = None;
+                return Some(Gesture::Short);
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_gestures() {
+    /// Feeds `(ms, level)` steps sampled every 5 ms and collects the gestures
+    fn run(steps: &[(u64, bool)], until: u64) -> Vec<(u64, Gesture)> {
+        let mut detector = GestureDetector::default();
+        let mut gestures = Vec::new();
+        let mut level = false;
+        let mut steps = steps.iter().peekable();
+        for t in (0..until).step_by(5) {
+            while let Some(&(_, l)) = steps.next_if(|(at, _)| *at <= t) {
+                level = l;
+            }
+            if let Some(g) = detector.update(level, Duration::from_millis(t)) {
+                gestures.push((t, g));
+            }
+        }
+        gestures
+    }
+
+    // Bouncing contacts give a single short press, once the double press gap has passed
+    let bouncy_press = [
+        (100, true),
+        (105, false),
+        (110, true),
+        (250, false),
+        (255, true),
+        (260, false),
+    ];
+    let short = run(&bouncy_press, 1000);
+    assert_eq!(short.len(), 1);
+    assert_eq!(short[0].1, Gesture::Short);
+    assert!(short[0].0 >= 260 + 300 && short[0].0 < 260 + 350);
+
+    let long = run(&[(100, true), (1500, false)], 2000);
+    assert_eq!(long.len(), 1);
+    assert_eq!(long[0].1, Gesture::Long);
+    // Reported while the button is still held
+    assert!(long[0].0 < 900);
+
+    let double = run(
+        &[(100, true), (200, false), (400, true), (500, false)],
+        2000,
+    );
+    assert_eq!(double.len(), 1);
+    assert_eq!(double[0].1, Gesture::Double);
+
+    // Too slow for a double press
+    let two_short = run(
+        &[(100, true), (200, false), (700, true), (800, false)],
+        2000,
+    );
+    let gestures: Vec<Gesture> = two_short.iter().map(|g| g.1).collect();
+    assert_eq!(gestures, [Gesture::Short, Gesture::Short]);
+
+    // Noise shorter than the debounce time is ignored
+    assert!(run(
+        &[(100, true), (120, false), (300, true), (310, false)],
+        1000
+    )
+    .is_empty());
+
+    let config: ButtonConfig =
+        serde_json::from_str(r#"{"short": "snooze_sunrise", "long": null, "double": null}"#)
+            .unwrap();
+    assert_eq!(
+        config.action(Gesture::Short),
+        Some(ButtonAction::SnoozeSunrise)
+    );
+    assert_eq!(config.action(Gesture::Long), None);
+    assert_eq!(config.snooze_minutes, 9);
+}
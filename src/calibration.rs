@@ -0,0 +1,231 @@
+use std::hash::{Hash, Hasher};
+
+use crate::color::extract_white;
+
+/// Maps perceived brightness (0..1) to light output (0..1).
+#[derive(PartialEq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrightnessCurve {
+    /// `output = brightness^exponent`
+    Gamma(f32),
+    /// The CIE 1976 lightness curve, with brightness being `L*/100`
+    CieLightness,
+}
+
+impl BrightnessCurve {
+    pub fn apply(self, brightness: f32) -> f32 {
+        let v = brightness.clamp(0.0, 1.0);
+        match self {
+            BrightnessCurve::Gamma(exponent) => v.powf(exponent),
+            BrightnessCurve::CieLightness => {
+                if v > 0.08 {
+                    ((v + 0.16) / 1.16).powi(3)
+                } else {
+                    v / 9.033
+                }
+            }
+        }
+    }
+}
+
+/// Calibration of the output stage for the red, green, blue and white PWM strips.
+#[derive(PartialEq, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OutputCalibration {
+    pub curve: BrightnessCurve,
+    /// Mixes the requested linear red, green and blue (columns) into the linear output of each strip (rows)
+    pub white_balance: [[f32; 3]; 3],
+    /// Highest duty cycle (0..1) of each strip, to limit the current
+    pub max_duty: [f32; 3],
+    /// Highest duty cycle (0..1) of the white strip
+    #[serde(default = "full_duty")]
+    pub white_max_duty: f32,
+    /// Color of the white strip in linear red, green and blue. When set, the part of every color
+    /// that the white strip can produce is moved to it.
+    #[serde(default)]
+    pub white_point: Option<[f32; 3]>,
+}
+
+fn full_duty() -> f32 {
+    1.0
+}
+
+// Containers need Eq and Hash. None of the values are ever NaN after validation.
+impl Eq for OutputCalibration {}
+
+impl Hash for OutputCalibration {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.curve {
+            BrightnessCurve::Gamma(exponent) => exponent.to_bits().hash(state),
+            BrightnessCurve::CieLightness => 0u32.hash(state),
+        }
+        for v in self.white_balance.iter().flatten().chain(&self.max_duty) {
+            v.to_bits().hash(state);
+        }
+        self.white_max_duty.to_bits().hash(state);
+        self.white_point.map(|p| p.map(f32::to_bits)).hash(state);
+    }
+}
+
+impl Default for OutputCalibration {
+    fn default() -> Self {
+        Self {
+            curve: BrightnessCurve::Gamma(2.0),
+            white_balance: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            max_duty: [1.0; 3],
+            white_max_duty: 1.0,
+            white_point: None,
+        }
+    }
+}
+
+/// Limits on the light output, applied in linear light after the calibration.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct OutputLimits {
+    /// Percent of the full light output that is never exceeded
+    pub ceiling: u32,
+    /// Percent of the full light output that is not exceeded while the schedule is in the night scene
+    pub night_cap: u32,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            ceiling: 100,
+            night_cap: 100,
+        }
+    }
+}
+
+impl OutputLimits {
+    /// Factor to multiply every duty with
+    pub fn scale(&self, is_night: bool) -> f32 {
+        let limit = if is_night {
+            self.ceiling.min(self.night_cap)
+        } else {
+            self.ceiling
+        };
+        limit.min(100) as f32 / 100.0
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum CalibrationError {
+    #[error("the gamma exponent must be between 0.1 and 5")]
+    InvalidGamma,
+    #[error("the white balance matrix must only contain values between 0 and 2")]
+    InvalidWhiteBalance,
+    #[error("the max duty of every channel must be between 0 and 1")]
+    InvalidMaxDuty,
+    #[error("the white point must only contain values between 0 and 1, and not be black")]
+    InvalidWhitePoint,
+}
+
+impl OutputCalibration {
+    pub fn validate(&self) -> Result<(), CalibrationError> {
+        if let BrightnessCurve::Gamma(exponent) = self.curve {
+            if !(0.1..=5.0).contains(&exponent) {
+                return Err(CalibrationError::InvalidGamma);
+            }
+        }
+        if !self
+            .white_balance
+            .iter()
+            .flatten()
+            .all(|v| (0.0..=2.0).contains(v))
+        {
+            return Err(CalibrationError::InvalidWhiteBalance);
+        }
+        if !self
+            .max_duty
+            .iter()
+            .chain([&self.white_max_duty])
+            .all(|v| (0.0..=1.0).contains(v))
+        {
+            return Err(CalibrationError::InvalidMaxDuty);
+        }
+        if let Some(white_point) = self.white_point {
+            if !white_point.iter().all(|v| (0.0..=1.0).contains(v))
+                || white_point.iter().all(|&v| v == 0.0)
+            {
+                return Err(CalibrationError::InvalidWhitePoint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the perceived brightness (0..1) of each channel to the duty cycle (0..1) of each strip.
+    pub fn apply(&self, color: [f32; 3]) -> [f32; 3] {
+        let [r, g, b, _] = self.apply_rgbw([color[0], color[1], color[2], 0.0]);
+        [r, g, b]
+    }
+
+    /// Like [`Self::apply`], but with the white strip as the fourth channel.
+    pub fn apply_rgbw(&self, color: [f32; 4]) -> [f32; 4] {
+        let mut linear = color.map(|c| self.curve.apply(c));
+        if let Some(white_point) = self.white_point {
+            linear = extract_white(linear, white_point);
+        }
+        let mut duty = [0.0; 4];
+        for (i, row) in self.white_balance.iter().enumerate() {
+            let mixed: f32 = row.iter().zip(&linear).map(|(w, c)| w * c).sum();
+            duty[i] = mixed.clamp(0.0, 1.0) * self.max_duty[i];
+        }
+        duty[3] = linear[3].clamp(0.0, 1.0) * self.white_max_duty;
+        duty
+    }
+}
+
+#[test]
+fn test_output_calibration() {
+    let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-4);
+
+    let default = OutputCalibration::default();
+    assert_eq!(default.validate(), Ok(()));
+    assert!(close(default.apply([0.0, 0.5, 1.0]), [0.0, 0.25, 1.0]));
+
+    let cie = OutputCalibration {
+        curve: BrightnessCurve::CieLightness,
+        ..Default::default()
+    };
+    let out = cie.apply([0.0, 0.5, 1.0]);
+    assert!(close(out, [0.0, 0.18419, 1.0]), "{out:?}");
+    // The curve is continuous where the linear and cubic parts meet
+    assert!((cie.curve.apply(0.08) - cie.curve.apply(0.0800001)).abs() < 1e-5);
+
+    let calibrated: OutputCalibration = serde_json::from_str(
+        r#"{
+            "curve": {"gamma": 1.0},
+            "white_balance": [[1.0, 0.0, 0.0], [0.0, 0.8, 0.0], [0.0, 0.1, 0.9]],
+            "max_duty": [0.5, 1.0, 1.0]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(calibrated.validate(), Ok(()));
+    assert!(close(calibrated.apply([1.0, 1.0, 0.5]), [0.5, 0.8, 0.55]));
+
+    // The white strip takes over the shared part of the color
+    let rgbw = OutputCalibration {
+        curve: BrightnessCurve::Gamma(1.0),
+        white_max_duty: 0.5,
+        white_point: Some([1.0; 3]),
+        ..Default::default()
+    };
+    assert_eq!(rgbw.validate(), Ok(()));
+    let out = rgbw.apply_rgbw([0.5, 0.4, 0.4, 0.2]);
+    assert!(close([out[0], out[1], out[2]], [0.1, 0.0, 0.0]), "{out:?}");
+    assert!((out[3] - 0.3).abs() < 1e-4, "{out:?}");
+
+    let limits = OutputLimits {
+        ceiling: 80,
+        night_cap: 10,
+    };
+    assert_eq!(limits.scale(false), 0.8);
+    assert_eq!(limits.scale(true), 0.1);
+    assert_eq!(OutputLimits::default().scale(true), 1.0);
+
+    let invalid = OutputCalibration {
+        max_duty: [1.5, 1.0, 1.0],
+        ..Default::default()
+    };
+    assert_eq!(invalid.validate(), Err(CalibrationError::InvalidMaxDuty));
+}
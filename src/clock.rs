@@ -0,0 +1,238 @@
+//! Keeping track of how far the system clock can be trusted.
+//!
+//! The ESP32 keeps its system clock running over soft resets, like a panic or an OTA update,
+//! but it starts at 1970 after a power cut. Between SNTP syncs the clock drifts with the crystal,
+//! so the time since the last sync gives a bound on how wrong it can be.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// How much the time can be trusted.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TimeQuality {
+    /// Never synced since the power was turned on, or obviously wrong
+    Unknown,
+    /// Running on its own since an earlier sync, and off by at most `max_error`
+    Estimated { max_error: Duration },
+    /// Synced recently
+    Synced,
+}
+
+impl TimeQuality {
+    pub fn is_known(&self) -> bool {
+        *self != TimeQuality::Unknown
+    }
+
+    /// Bound on how wrong the time is, `None` if it is unknown
+    pub fn max_error(&self) -> Option<Duration> {
+        match self {
+            TimeQuality::Unknown => None,
+            TimeQuality::Estimated { max_error } => Some(*max_error),
+            TimeQuality::Synced => Some(Duration::ZERO),
+        }
+    }
+}
+
+/// What the [`TimeSource`] needs to remember over a soft reset.
+///
+/// Stored in memory that is not cleared on a soft reset, so it is checksummed to detect the
+/// garbage it holds after a power cut.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SavedClock {
+    /// Unix time of the last sync, in seconds
+    last_sync: i64,
+    /// Drift of the clock in parts per billion, [`i32::MIN`] if it has not been measured
+    drift_ppb: i32,
+    checksum: u32,
+}
+
+impl SavedClock {
+    fn checksum(last_sync: i64, drift_ppb: i32) -> u32 {
+        let sum = (last_sync as u64).rotate_left(13) ^ (drift_ppb as u32 as u64) ^ 0x5eed_c10c;
+        (sum as u32) ^ ((sum >> 32) as u32)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.checksum == Self::checksum(self.last_sync, self.drift_ppb)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncPoint {
+    utc: DateTime<Utc>,
+    /// Monotonic time of the sync, `None` if it happened before the last reset
+    monotonic: Option<Duration>,
+}
+
+/// Tracks the SNTP syncs of the system clock to tell how much it can be trusted.
+#[derive(Debug, Clone)]
+pub struct TimeSource {
+    /// The clock can't be earlier than this, e.g. the build time of the firmware
+    not_before: DateTime<Utc>,
+    last_sync: Option<SyncPoint>,
+    /// Measured drift, as the fraction the clock runs too fast
+    drift: Option<f64>,
+}
+
+/// Drift assumed before it has been measured. Crystals are usually within 20 ppm, but the clock
+/// runs from a less accurate oscillator while the CPU sleeps.
+const DEFAULT_DRIFT: f64 = 200e-6;
+/// Margin on top of a measured drift, which changes with the temperature
+const DRIFT_MARGIN: f64 = 20e-6;
+/// Error of a sync, from network delays
+const SYNC_ERROR: Duration = Duration::from_secs(1);
+/// The time counts as synced for this long after a sync. SNTP syncs every hour by default.
+const SYNC_VALID_FOR: Duration = Duration::from_secs(2 * 60 * 60);
+/// Syncs closer together than this don't give a useful drift measurement
+const MIN_DRIFT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Beyond this error the time is not useful for the schedule
+pub const MAX_ESTIMATED_ERROR: Duration = Duration::from_secs(15 * 60);
+
+impl TimeSource {
+    pub fn new(not_before: DateTime<Utc>) -> Self {
+        Self {
+            not_before,
+            last_sync: None,
+            drift: None,
+        }
+    }
+
+    /// Continues from the state saved before a soft reset, if it is valid.
+    pub fn restore(not_before: DateTime<Utc>, saved: &SavedClock) -> Self {
+        let mut source = Self::new(not_before);
+        if saved.is_valid() {
+            source.last_sync = DateTime::from_timestamp(saved.last_sync, 0).map(|utc| SyncPoint {
+                utc,
+                monotonic: None,
+            });
+            source.drift = (saved.drift_ppb != i32::MIN).then_some(saved.drift_ppb as f64 * 1e-9);
+        }
+        source
+    }
+
+    pub fn save(&self) -> SavedClock {
+        let last_sync = self.last_sync.map_or(0, |s| s.utc.timestamp());
+        let drift_ppb = self.drift.map_or(i32::MIN, |d| (d * 1e9) as i32);
+        SavedClock {
+            last_sync,
+            drift_ppb,
+            checksum: SavedClock::checksum(last_sync, drift_ppb),
+        }
+    }
+
+    /// Records that the clock was set to `utc` by SNTP at the monotonic time `monotonic`.
+    pub fn synced(&mut self, utc: DateTime<Utc>, monotonic: Duration) {
+        if let Some(SyncPoint {
+            utc: last_utc,
+            monotonic: Some(last_monotonic),
+        }) = self.last_sync
+        {
+            // How much more time passed on the local clock than really did
+            let local = monotonic.saturating_sub(last_monotonic);
+            if local >= MIN_DRIFT_INTERVAL {
+                let real = (utc - last_utc).num_milliseconds() as f64 / 1000.0;
+                let drift = (local.as_secs_f64() - real) / real;
+                // Smooth the measurements, a single one is affected by the sync errors
+                self.drift = Some(match self.drift {
+                    Some(previous) => previous + (drift - previous) * 0.3,
+                    None => drift,
+                });
+            } else {
+                // Too close to the last sync to measure anything, keep the older sync point
+                return;
+            }
+        }
+        self.last_sync = Some(SyncPoint {
+            utc,
+            monotonic: Some(monotonic),
+        });
+    }
+
+    /// Measured drift in parts per million, if any
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.drift.map(|d| d * 1e6)
+    }
+
+    pub fn quality(&self, now: DateTime<Utc>) -> TimeQuality {
+        let Some(last_sync) = self.last_sync else {
+            return TimeQuality::Unknown;
+        };
+        if now < self.not_before || now < last_sync.utc {
+            return TimeQuality::Unknown;
+        }
+        let since_sync = (now - last_sync.utc).to_std().unwrap_or_default();
+        let drift = self.drift.map_or(DEFAULT_DRIFT, |d| d.abs() + DRIFT_MARGIN);
+        let max_error = SYNC_ERROR + since_sync.mul_f64(drift);
+        if last_sync.monotonic.is_some() && since_sync < SYNC_VALID_FOR {
+            TimeQuality::Synced
+        } else if max_error <= MAX_ESTIMATED_ERROR {
+            TimeQuality::Estimated { max_error }
+        } else {
+            TimeQuality::Unknown
+        }
+    }
+}
+
+#[test]
+fn test_time_source() {
+    let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let built = at("2024-01-01T00:00:00Z");
+    let mut source = TimeSource::new(built);
+    assert_eq!(
+        source.quality(at("1970-01-01T00:00:05Z")),
+        TimeQuality::Unknown
+    );
+    assert_eq!(
+        source.quality(at("2024-03-01T12:00:00Z")),
+        TimeQuality::Unknown
+    );
+
+    source.synced(at("2024-03-01T12:00:00Z"), Duration::from_secs(10));
+    assert_eq!(
+        source.quality(at("2024-03-01T12:30:00Z")),
+        TimeQuality::Synced
+    );
+    // Without syncs for a day, the error grows with the default drift
+    let quality = source.quality(at("2024-03-02T12:00:00Z"));
+    assert_eq!(quality.max_error().map(|e| e.as_secs()), Some(18));
+
+    // The clock runs 50 ppm fast
+    let hour = 60 * 60;
+    source.synced(
+        at("2024-03-01T13:00:00Z"),
+        Duration::from_secs_f64(10.0 + hour as f64 * (1.0 + 50e-6)),
+    );
+    assert!((source.drift_ppm().unwrap() - 50.0).abs() < 0.1);
+
+    // The last sync and the drift survive a soft reset, but the time is only estimated
+    let restored = TimeSource::restore(built, &source.save());
+    assert!((restored.drift_ppm().unwrap() - 50.0).abs() < 0.1);
+    let quality = restored.quality(at("2024-03-01T13:05:00Z"));
+    assert!(
+        matches!(quality, TimeQuality::Estimated { .. }),
+        "{quality:?}"
+    );
+    // A clock reset to 1970 by a power cut is never trusted
+    assert_eq!(
+        restored.quality(at("1970-01-01T00:00:05Z")),
+        TimeQuality::Unknown
+    );
+    // And neither is one that has run for too long on its own
+    assert_eq!(
+        restored.quality(at("2025-03-01T00:00:00Z")),
+        TimeQuality::Unknown
+    );
+
+    // Garbage from memory after a power cut
+    let garbage = SavedClock {
+        last_sync: 1_709_294_400,
+        drift_ppb: 0,
+        checksum: 0xdead_beef,
+    };
+    let restored = TimeSource::restore(built, &garbage);
+    assert_eq!(
+        restored.quality(at("2024-03-01T13:05:00Z")),
+        TimeQuality::Unknown
+    );
+}
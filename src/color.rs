@@ -0,0 +1,687 @@
+#[derive(PartialEq, Eq, Clone, Hash, Copy)]
+pub struct RGBColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl std::fmt::Debug for RGBColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({},{},{})", self.r, self.g, self.b)
+    }
+}
+
+impl serde::Serialize for RGBColor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let rgb = format!("rgb({},{},{})", self.r, self.g, self.b);
+        serializer.serialize_str(&rgb)
+    }
+}
+
+impl From<RGBColor> for [f32; 4] {
+    fn from(color: RGBColor) -> Self {
+        [color.r as f32, color.g as f32, color.b as f32, 0.0]
+    }
+}
+
+/// Accepts the same formats as [`RGBWColor`], as long as the white channel is off.
+impl<'de> serde::Deserialize<'de> for RGBColor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let color = RGBWColor::deserialize(deserializer)?;
+        RGBColor::try_from(color).map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::str::FromStr for RGBColor {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<RGBWColor>()?.try_into()
+    }
+}
+
+impl TryFrom<RGBWColor> for RGBColor {
+    type Error = ColorParseError;
+
+    fn try_from(color: RGBWColor) -> Result<Self, Self::Error> {
+        if color.w != 0 {
+            return Err(ColorParseError::WhiteNotSupported);
+        }
+        Ok(RGBColor {
+            r: color.r,
+            g: color.g,
+            b: color.b,
+        })
+    }
+}
+
+/// A color for lights with a separate white channel.
+#[derive(PartialEq, Eq, Clone, Hash, Copy, Default)]
+pub struct RGBWColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl std::fmt::Debug for RGBWColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({},{},{},{})", self.r, self.g, self.b, self.w)
+    }
+}
+
+impl From<RGBColor> for RGBWColor {
+    fn from(color: RGBColor) -> Self {
+        RGBWColor {
+            r: color.r,
+            g: color.g,
+            b: color.b,
+            w: 0,
+        }
+    }
+}
+
+impl From<RGBWColor> for [f32; 4] {
+    fn from(color: RGBWColor) -> Self {
+        [
+            color.r as f32,
+            color.g as f32,
+            color.b as f32,
+            color.w as f32,
+        ]
+    }
+}
+
+impl serde::Serialize for RGBWColor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let rgbw = format!("rgbw({},{},{},{})", self.r, self.g, self.b, self.w);
+        serializer.serialize_str(&rgbw)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, thiserror::Error)]
+pub enum ColorParseError {
+    #[error("unknown color format '{0}', expected rgb(r,g,b), rgbw(r,g,b,w), #rrggbb, hsv(h,s%,v%) or a color temperature like '2200K at 30%'")]
+    UnknownFormat(String),
+    #[error("expected {expected} channels but got {got}")]
+    WrongChannelCount { expected: usize, got: usize },
+    #[error("'{0}' is not a valid channel value (0..255)")]
+    InvalidChannel(String),
+    #[error("invalid hex color '{0}', expected #rrggbb or #rrggbbww")]
+    InvalidHex(String),
+    #[error("'{0}' is out of range, the hue is 0..360 and saturation and value are 0..100%")]
+    InvalidHsv(String),
+    #[error("the color temperature must be between {}K and {}K", KELVIN_RANGE.start(), KELVIN_RANGE.end())]
+    KelvinOutOfRange,
+    #[error("'{0}' is not a valid brightness (0..100%)")]
+    InvalidBrightness(String),
+    #[error("the color has a white channel, which this light does not support")]
+    WhiteNotSupported,
+}
+
+/// Color temperatures that [`kelvin_to_rgb`] can approximate
+pub const KELVIN_RANGE: std::ops::RangeInclusive<f32> = 1000.0..=40000.0;
+
+/// Parses `name(a,b,c...)`. Returns `None` if the string does not start with `name(`.
+fn parse_function<'a>(s: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    let args = s
+        .strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')?;
+    Some(args.split(',').map(|s| s.trim()).collect())
+}
+
+fn parse_channels<const N: usize>(args: &[&str]) -> Result<[u8; N], ColorParseError> {
+    if args.len() != N {
+        return Err(ColorParseError::WrongChannelCount {
+            expected: N,
+            got: args.len(),
+        });
+    }
+    let mut channels = [0; N];
+    for (channel, arg) in channels.iter_mut().zip(args) {
+        *channel = arg
+            .parse()
+            .map_err(|_| ColorParseError::InvalidChannel(arg.to_string()))?;
+    }
+    Ok(channels)
+}
+
+/// Parses a percentage, with or without the `%` sign
+fn parse_percent(s: &str) -> Option<f32> {
+    let v: f32 = s.trim().trim_end_matches('%').trim_end().parse().ok()?;
+    (0.0..=100.0).contains(&v).then_some(v)
+}
+
+fn parse_hex(hex: &str) -> Result<RGBWColor, ColorParseError> {
+    let invalid = || ColorParseError::InvalidHex(format!("#{hex}"));
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut channels = [0; 4];
+    for (channel, i) in channels.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid())?;
+    }
+    let [r, g, b, w] = channels;
+    Ok(RGBWColor { r, g, b, w })
+}
+
+fn parse_hsv(args: &[&str]) -> Result<RGBWColor, ColorParseError> {
+    if args.len() != 3 {
+        return Err(ColorParseError::WrongChannelCount {
+            expected: 3,
+            got: args.len(),
+        });
+    }
+    let invalid = |arg: &str| ColorParseError::InvalidHsv(arg.to_string());
+    let h: f32 = args[0]
+        .trim_end_matches("deg")
+        .parse()
+        .ok()
+        .filter(|h| (0.0..=360.0).contains(h))
+        .ok_or_else(|| invalid(args[0]))?;
+    let s = parse_percent(args[1]).ok_or_else(|| invalid(args[1]))?;
+    let v = parse_percent(args[2]).ok_or_else(|| invalid(args[2]))?;
+    Ok(RGBWColor::from_f32(hsv_to_rgb(h, s / 100.0, v / 100.0)))
+}
+
+/// Parses `2200K` or `2200K at 30%`
+fn parse_kelvin(s: &str) -> Option<Result<RGBWColor, ColorParseError>> {
+    let (temperature, brightness) = match s.split_once(" at ") {
+        Some((t, b)) => (t.trim(), Some(b.trim())),
+        None => (s, None),
+    };
+    let kelvin: f32 = temperature
+        .strip_suffix(['K', 'k'])?
+        .trim_end()
+        .parse()
+        .ok()?;
+    Some(color_temperature(kelvin, brightness))
+}
+
+fn color_temperature(kelvin: f32, brightness: Option<&str>) -> Result<RGBWColor, ColorParseError> {
+    if !KELVIN_RANGE.contains(&kelvin) {
+        return Err(ColorParseError::KelvinOutOfRange);
+    }
+    let brightness = match brightness {
+        Some(b) => {
+            parse_percent(b).ok_or_else(|| ColorParseError::InvalidBrightness(b.to_string()))?
+        }
+        None => 100.0,
+    };
+    // Dimmed in linear light, like the brightness of a [`SceneLight`]
+    Ok(RGBWColor::from_f32(
+        kelvin_to_rgb(kelvin).map(|c| dim(c, brightness / 100.0)),
+    ))
+}
+
+impl RGBWColor {
+    /// Rounds a color in the range 0..255 per channel, with the white channel off
+    fn from_f32(rgb: [f32; 3]) -> Self {
+        let [r, g, b] = rgb.map(|c| c.round().clamp(0.0, 255.0) as u8);
+        RGBWColor { r, g, b, w: 0 }
+    }
+}
+
+/// Accepts `rgbw(r,g,b,w)`, `rgb(r,g,b)`, `#rrggbb`, `#rrggbbww`, `hsv(h,s%,v%)` and color
+/// temperatures like `2200K` or `2200K at 30%`.
+impl std::str::FromStr for RGBWColor {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(args) = parse_function(s, "rgbw") {
+            let [r, g, b, w] = parse_channels(&args)?;
+            Ok(RGBWColor { r, g, b, w })
+        } else if let Some(args) = parse_function(s, "rgb") {
+            let [r, g, b] = parse_channels(&args)?;
+            Ok(RGBWColor { r, g, b, w: 0 })
+        } else if let Some(args) = parse_function(s, "hsv") {
+            parse_hsv(&args)
+        } else if let Some(hex) = s.strip_prefix('#') {
+            parse_hex(hex)
+        } else if let Some(color) = parse_kelvin(s) {
+            color
+        } else {
+            Err(ColorParseError::UnknownFormat(s.to_string()))
+        }
+    }
+}
+
+/// The JSON object form of a color, as sent by some home automation frontends.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ColorObject {
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    w: Option<u8>,
+    kelvin: Option<f32>,
+    /// Percent
+    brightness: Option<f32>,
+}
+
+impl ColorObject {
+    fn into_color(self) -> Result<RGBWColor, String> {
+        match self {
+            ColorObject {
+                r: Some(r),
+                g: Some(g),
+                b: Some(b),
+                w,
+                kelvin: None,
+                brightness: None,
+            } => Ok(RGBWColor {
+                r,
+                g,
+                b,
+                w: w.unwrap_or(0),
+            }),
+            ColorObject {
+                r: None,
+                g: None,
+                b: None,
+                w: None,
+                kelvin: Some(kelvin),
+                brightness,
+            } => color_temperature(kelvin, brightness.map(|b| b.to_string()).as_deref())
+                .map_err(|e| e.to_string()),
+            _ => Err(
+                "a color object needs either r, g, b (and optionally w), or kelvin (and optionally brightness)"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+struct ColorVisitor;
+
+impl<'de> serde::de::Visitor<'de> for ColorVisitor {
+    type Value = RGBWColor;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a color string like rgb(r,g,b) or #rrggbb, or an object with r, g and b")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let object: ColorObject =
+            serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        object.into_color().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RGBWColor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
+
+/// Converts a hue (0..360 degrees), saturation and value (0..1) to a color in the range 0..255.
+pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
+    let c = v * s;
+    let h = (h % 360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [r, g, b].map(|c| (c + m) * 255.0)
+}
+
+/// Approximates the color (0..255) of a black body at the given temperature.
+///
+/// Uses Tanner Helland's fit to the CIE 1964 color matching functions, which is accurate enough
+/// for picking light colors and clamps to [`KELVIN_RANGE`].
+pub fn kelvin_to_rgb(kelvin: f32) -> [f32; 3] {
+    let t = kelvin.clamp(*KELVIN_RANGE.start(), *KELVIN_RANGE.end()) / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let g = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12217 * (t - 60.0).powf(-0.075514846)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.04478
+    };
+    [r, g, b].map(|c| c.clamp(0.0, 255.0))
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// A scene color with a separate brightness, so a scene can be dimmed without picking a new color.
+///
+/// Deserializes from a plain color (at full brightness), or from an object like
+/// `{"color": "2200K", "brightness": 30}`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash, serde::Serialize)]
+pub struct SceneLight {
+    pub color: RGBWColor,
+    /// Percent of the light output of `color`
+    pub brightness: u32,
+}
+
+impl From<RGBWColor> for SceneLight {
+    fn from(color: RGBWColor) -> Self {
+        SceneLight {
+            color,
+            brightness: 100,
+        }
+    }
+}
+
+/// The color (0..255) with the brightness applied in linear light, so that 50% brightness is half
+/// the light output of the color.
+impl From<SceneLight> for [f32; 4] {
+    fn from(light: SceneLight) -> Self {
+        let color = <[f32; 4]>::from(light.color);
+        if light.brightness >= 100 {
+            return color;
+        }
+        color.map(|c| dim(c, light.brightness as f32 / 100.0))
+    }
+}
+
+/// Scales the light output of a channel value (0..255) by `scale`
+fn dim(c: f32, scale: f32) -> f32 {
+    linear_to_srgb(srgb_to_linear(c / 255.0) * scale) * 255.0
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneLightObject {
+    color: RGBWColor,
+    #[serde(default = "full_brightness")]
+    brightness: u32,
+}
+
+fn full_brightness() -> u32 {
+    100
+}
+
+struct SceneLightVisitor;
+
+impl<'de> serde::de::Visitor<'de> for SceneLightVisitor {
+    type Value = SceneLight;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a color string, or an object with a color and a brightness")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse::<RGBWColor>()
+            .map(SceneLight::from)
+            .map_err(E::custom)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let object: SceneLightObject =
+            serde::Deserialize::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        if object.brightness > 100 {
+            return Err(serde::de::Error::custom(
+                "the brightness must be between 0 and 100%",
+            ));
+        }
+        Ok(SceneLight {
+            color: object.color,
+            brightness: object.brightness,
+        })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SceneLight {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(SceneLightVisitor)
+    }
+}
+
+/// Moves the white part of a color (the amount all of red, green and blue share) to the white
+/// channel. All channels are linear light in the range 0..1.
+///
+/// `white_point` is the color of the white LEDs as seen through the red, green and blue channels,
+/// e.g. `[1.0, 0.8, 0.6]` for warm white LEDs. The extracted white is limited so that the color
+/// never needs negative red, green or blue.
+pub fn extract_white(color: [f32; 4], white_point: [f32; 3]) -> [f32; 4] {
+    let [r, g, b, w] = color;
+    let white = [r, g, b]
+        .iter()
+        .zip(&white_point)
+        .filter(|(_, &p)| p > 0.0)
+        .map(|(c, p)| c / p)
+        .fold(f32::INFINITY, f32::min);
+    let white = if white.is_finite() {
+        white.clamp(0.0, 1.0 - w.clamp(0.0, 1.0))
+    } else {
+        0.0
+    };
+    [
+        r - white * white_point[0],
+        g - white * white_point[1],
+        b - white * white_point[2],
+        w + white,
+    ]
+    .map(|c| c.clamp(0.0, 1.0))
+}
+
+pub fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    let mut res = [0.0; 4];
+    for i in 0..4 {
+        res[i] = (a[i] + (b[i] - a[i]) * t).clamp(0.0, 255.0);
+    }
+    res
+}
+
+#[test]
+fn test_lerp() {
+    let a = [0.0, 0.0, 0.0, 0.0];
+    let b = [100.0, 200.0, 300.0, 400.0];
+    let r = lerp(a, b, 0.5);
+    assert_eq!(r, [50.0, 100.0, 150.0, 200.0]);
+    assert_eq!(lerp(a, b, 0.0), a);
+    assert_eq!(lerp(a, b, 1.0), [100.0, 200.0, 255.0, 255.0]);
+}
+
+#[test]
+fn test_rgbw_color() {
+    let parse = |s: &str| serde_json::from_str::<RGBWColor>(s);
+    let color = parse(r#""rgbw(1, 2, 3, 4)""#).unwrap();
+    assert_eq!(<[f32; 4]>::from(color), [1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(serde_json::to_string(&color).unwrap(), r#""rgbw(1,2,3,4)""#);
+    assert_eq!(parse(r#""rgb(1,2,3)""#).unwrap().w, 0);
+    assert!(parse(r#""rgbw(1,2,3)""#).is_err());
+    assert!(parse(r#""rgbw(1,2,3,256)""#).is_err());
+
+    assert_eq!(
+        extract_white([0.5, 0.5, 0.5, 0.0], [1.0; 3]),
+        [0.0, 0.0, 0.0, 0.5]
+    );
+    assert_eq!(
+        extract_white([0.75, 0.5, 0.25, 0.0], [1.0; 3]),
+        [0.5, 0.25, 0.0, 0.25]
+    );
+    // Warm white LEDs can not replace blue
+    let out = extract_white([0.8, 0.5, 0.3, 0.0], [1.0, 0.5, 0.0]);
+    assert!(
+        out.iter()
+            .zip([0.0, 0.1, 0.3, 0.8])
+            .all(|(a, b)| (a - b).abs() < 1e-6),
+        "{out:?}"
+    );
+    // The white channel can not go above full
+    assert_eq!(
+        extract_white([1.0, 1.0, 1.0, 0.75], [1.0; 3]),
+        [0.75, 0.75, 0.75, 1.0]
+    );
+}
+
+#[test]
+fn test_color_formats() {
+    let parse = |s: &str| s.parse::<RGBWColor>();
+    let rgb = |r, g, b| RGBWColor { r, g, b, w: 0 };
+
+    assert_eq!(parse("#ff8000"), Ok(rgb(255, 128, 0)));
+    assert_eq!(parse("#FF800010").unwrap().w, 16);
+    assert_eq!(parse("hsv(0, 100%, 100%)"), Ok(rgb(255, 0, 0)));
+    assert_eq!(parse("hsv(120,100,50)"), Ok(rgb(0, 128, 0)));
+    assert_eq!(parse("hsv(240, 50%, 100%)"), Ok(rgb(128, 128, 255)));
+    assert_eq!(parse("6600K"), Ok(rgb(255, 255, 255)));
+    assert_eq!(parse("2200K"), Ok(rgb(255, 146, 39)));
+    let dim = parse("2200K at 30%").unwrap();
+    // 30% of the light output, not of the sRGB values
+    assert_eq!(dim, rgb(149, 83, 18));
+
+    assert_eq!(
+        parse("rgb(1,2)"),
+        Err(ColorParseError::WrongChannelCount {
+            expected: 3,
+            got: 2
+        })
+    );
+    assert_eq!(
+        parse("rgb(1,2,300)"),
+        Err(ColorParseError::InvalidChannel("300".to_string()))
+    );
+    assert!(matches!(
+        parse("#ff80"),
+        Err(ColorParseError::InvalidHex(_))
+    ));
+    assert!(matches!(
+        parse("#ff80zz"),
+        Err(ColorParseError::InvalidHex(_))
+    ));
+    assert!(matches!(
+        parse("hsv(400,0,0)"),
+        Err(ColorParseError::InvalidHsv(_))
+    ));
+    assert_eq!(parse("500K"), Err(ColorParseError::KelvinOutOfRange));
+    assert!(matches!(
+        parse("2200K at 130%"),
+        Err(ColorParseError::InvalidBrightness(_))
+    ));
+    assert!(matches!(
+        parse("orange"),
+        Err(ColorParseError::UnknownFormat(_))
+    ));
+    assert_eq!(
+        "rgbw(1,2,3,4)".parse::<RGBColor>(),
+        Err(ColorParseError::WhiteNotSupported)
+    );
+
+    // JSON objects
+    let json = |s: &str| serde_json::from_str::<RGBWColor>(s);
+    assert_eq!(json(r#"{"r": 1, "g": 2, "b": 3}"#).unwrap(), rgb(1, 2, 3));
+    assert_eq!(json(r#"{"r": 1, "g": 2, "b": 3, "w": 4}"#).unwrap().w, 4);
+    assert_eq!(json(r#"{"kelvin": 2200, "brightness": 30}"#).unwrap(), dim);
+    assert!(json(r#"{"r": 1, "g": 2}"#).is_err());
+    assert!(json(r#"{"r": 1, "g": 2, "b": 3, "x": 0}"#).is_err());
+    let error = json(r#""hsv(1,2)""#).unwrap_err().to_string();
+    assert!(error.contains("expected 3 channels but got 2"), "{error}");
+    assert_eq!(
+        serde_json::from_str::<RGBColor>(r##""#102030""##).unwrap(),
+        RGBColor {
+            r: 16,
+            g: 32,
+            b: 48
+        }
+    );
+
+    // Every format round-trips through the serialized form
+    for input in [
+        "#ff8000",
+        "hsv(200, 40%, 70%)",
+        "2200K at 30%",
+        "rgbw(1,2,3,4)",
+    ] {
+        let color = parse(input).unwrap();
+        let serialized = serde_json::to_string(&color).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RGBWColor>(&serialized).unwrap(),
+            color
+        );
+    }
+    let color: RGBColor = "#ff8000".parse().unwrap();
+    let serialized = serde_json::to_string(&color).unwrap();
+    assert_eq!(
+        serde_json::from_str::<RGBColor>(&serialized).unwrap(),
+        color
+    );
+}
+
+#[test]
+fn test_scene_light() {
+    let parse = |s: &str| serde_json::from_str::<SceneLight>(s);
+    let white = RGBWColor {
+        r: 255,
+        g: 255,
+        b: 255,
+        w: 0,
+    };
+
+    assert_eq!(parse(r#""rgb(255,255,255)""#).unwrap(), white.into());
+    let dimmed = parse(r#"{"color": "rgb(255,255,255)", "brightness": 50}"#).unwrap();
+    assert_eq!(dimmed.brightness, 50);
+    // Half the light output, not half the sRGB value
+    let out: [f32; 4] = dimmed.into();
+    assert!((out[0] - 187.5).abs() < 1.0, "{out:?}");
+    assert_eq!(out[3], 0.0);
+    assert_eq!(<[f32; 4]>::from(SceneLight::from(white))[0], 255.0);
+
+    let warm = parse(r#"{"color": "2200K", "brightness": 30}"#).unwrap();
+    assert_eq!(warm.color, "2200K".parse().unwrap());
+    assert!(parse(r#"{"color": "2200K", "brightness": 130}"#).is_err());
+    assert!(parse(r#"{"brightness": 30}"#).is_err());
+    // A brightness in the color string means the same as a separate one
+    let out: [f32; 4] = warm.into();
+    assert_eq!(
+        RGBWColor::from_f32([out[0], out[1], out[2]]),
+        "2200K at 30%".parse().unwrap()
+    );
+
+    let serialized = serde_json::to_string(&dimmed).unwrap();
+    assert_eq!(parse(&serialized).unwrap(), dimmed);
+}
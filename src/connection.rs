@@ -0,0 +1,330 @@
+//! State of the Wi-Fi connection, shared with the rest of the firmware through a watch channel,
+//! and the backoff between connection attempts.
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::roaming::format_bssid;
+
+/// Why the connection is down.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DisconnectReason {
+    /// Not tried to connect yet
+    NotStarted,
+    /// No association or address within the timeout
+    Timeout,
+    /// Connecting failed with this ESP-IDF error code
+    ConnectFailed(i32),
+    /// The connection was up and went down
+    LinkLost,
+    /// Disconnected on purpose to switch to a better access point
+    Roaming,
+}
+
+/// Reason code from an 802.11 disconnect, or one of the codes above 200 that ESP-IDF adds.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct WifiReason(pub u16);
+
+impl WifiReason {
+    /// Name of the common codes
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self.0 {
+            1 => "unspecified",
+            2 => "authentication expired",
+            3 => "deauthenticated because the access point left",
+            4 => "disassociated due to inactivity",
+            5 => "too many stations",
+            6 | 7 => "not authenticated",
+            8 => "disassociated because the station left",
+            15 => "4-way handshake timeout",
+            16 => "group key update timeout",
+            23 => "802.1X authentication failed",
+            200 => "beacon timeout",
+            201 => "no access point found",
+            202 => "authentication failed",
+            203 => "association failed",
+            204 => "handshake timeout",
+            205 => "connection failed",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for WifiReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name} ({})", self.0),
+            None => write!(f, "reason {}", self.0),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ConnectionState {
+    Disconnected {
+        reason: DisconnectReason,
+        /// What the driver reported for the last disconnect, if anything
+        wifi_reason: Option<WifiReason>,
+    },
+    Connecting {
+        ssid: String,
+        /// Attempts in a row, starting at 1
+        attempt: u32,
+    },
+    /// Associated with an access point, waiting for an address
+    Associated { ssid: String },
+    GotIp {
+        ssid: String,
+        bssid: [u8; 6],
+        /// In dBm, when connected
+        rssi: Option<i8>,
+        ip: Ipv4Addr,
+    },
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, ConnectionState::GotIp { .. })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    /// Failed connection attempts since boot
+    pub failures: u32,
+    /// Failed connection attempts since the last successful one
+    pub consecutive_failures: u32,
+    /// Successful connections after the first one
+    pub reconnects: u32,
+}
+
+/// What the Wi-Fi loop publishes.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct WifiStatus {
+    pub state: ConnectionState,
+    pub stats: ConnectionStats,
+    has_connected: bool,
+}
+
+impl Default for WifiStatus {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Disconnected {
+                reason: DisconnectReason::NotStarted,
+                wifi_reason: None,
+            },
+            stats: ConnectionStats::default(),
+            has_connected: false,
+        }
+    }
+}
+
+impl WifiStatus {
+    pub fn connecting(&mut self, ssid: &str) {
+        self.state = ConnectionState::Connecting {
+            ssid: ssid.to_string(),
+            attempt: self.stats.consecutive_failures + 1,
+        };
+    }
+
+    pub fn associated(&mut self, ssid: &str) {
+        self.state = ConnectionState::Associated {
+            ssid: ssid.to_string(),
+        };
+    }
+
+    pub fn got_ip(&mut self, ssid: &str, bssid: [u8; 6], rssi: Option<i8>, ip: Ipv4Addr) {
+        self.state = ConnectionState::GotIp {
+            ssid: ssid.to_string(),
+            bssid,
+            rssi,
+            ip,
+        };
+        self.stats.consecutive_failures = 0;
+        if self.has_connected {
+            self.stats.reconnects += 1;
+        }
+        self.has_connected = true;
+    }
+
+    /// A connection attempt failed.
+    pub fn failed(&mut self, reason: DisconnectReason, wifi_reason: Option<WifiReason>) {
+        self.state = ConnectionState::Disconnected {
+            reason,
+            wifi_reason,
+        };
+        self.stats.failures += 1;
+        self.stats.consecutive_failures += 1;
+    }
+
+    /// An established connection went down.
+    pub fn disconnected(&mut self, reason: DisconnectReason, wifi_reason: Option<WifiReason>) {
+        self.state = ConnectionState::Disconnected {
+            reason,
+            wifi_reason,
+        };
+    }
+}
+
+impl fmt::Display for WifiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.state {
+            ConnectionState::Disconnected {
+                reason,
+                wifi_reason,
+            } => {
+                write!(f, "Wi-Fi disconnected: {reason:?}")?;
+                if let Some(wifi_reason) = wifi_reason {
+                    write!(f, ", {wifi_reason}")?;
+                }
+            }
+            ConnectionState::Connecting { ssid, attempt } => {
+                write!(f, "Wi-Fi connecting to {ssid}, attempt {attempt}")?
+            }
+            ConnectionState::Associated { ssid } => {
+                write!(f, "Wi-Fi associated with {ssid}, waiting for an address")?
+            }
+            ConnectionState::GotIp {
+                ssid,
+                bssid,
+                rssi,
+                ip,
+            } => {
+                write!(f, "Wi-Fi connected to {ssid} ({}", format_bssid(bssid))?;
+                if let Some(rssi) = rssi {
+                    write!(f, ", {rssi} dBm")?;
+                }
+                write!(f, ") as {ip}")?;
+            }
+        }
+        write!(
+            f,
+            ", {} reconnects, {} failed attempts",
+            self.stats.reconnects, self.stats.failures
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Growth of the delay per attempt
+    pub multiplier: f64,
+    /// The delay is randomly changed by up to this fraction, so that devices that lost the
+    /// connection at the same time don't all retry at the same time
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+            multiplier: 2.0,
+            jitter: 0.25,
+        }
+    }
+}
+
+/// Exponentially growing delays between retries.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    config: BackoffConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self { config, attempt: 0 }
+    }
+
+    /// The delay before the next attempt. `random` is uniformly distributed in `0..1`.
+    pub fn next_delay(&mut self, random: f64) -> Duration {
+        let base = self.config.initial.as_secs_f64()
+            * self.config.multiplier.powi(self.attempt.min(64) as i32);
+        let max = self.config.max.as_secs_f64();
+        let jittered = base.min(max) * (1.0 + self.config.jitter * (2.0 * random - 1.0));
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_secs_f64(jittered.clamp(0.0, max))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(BackoffConfig {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(60),
+        multiplier: 2.0,
+        jitter: 0.5,
+    });
+    // Without jitter
+    let delays: Vec<_> = (0..8).map(|_| backoff.next_delay(0.5).as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+    // The jitter spreads the delays around, but never above the cap
+    backoff.reset();
+    assert_eq!(backoff.next_delay(0.0), Duration::from_millis(500));
+    assert_eq!(backoff.next_delay(1.0), Duration::from_secs(3));
+    for _ in 0..100 {
+        assert!(backoff.next_delay(1.0) <= Duration::from_secs(60));
+    }
+}
+
+#[test]
+fn test_wifi_status() {
+    let mut status = WifiStatus::default();
+    assert!(!status.state.is_connected());
+
+    status.connecting("Home");
+    status.failed(DisconnectReason::ConnectFailed(-1), Some(WifiReason(202)));
+    assert_eq!(
+        status.to_string(),
+        "Wi-Fi disconnected: ConnectFailed(-1), authentication failed (202), 0 reconnects, 1 failed attempts"
+    );
+    status.connecting("Home");
+    assert_eq!(
+        status.state,
+        ConnectionState::Connecting {
+            ssid: "Home".to_string(),
+            attempt: 2
+        }
+    );
+    status.associated("Home");
+    status.got_ip(
+        "Home",
+        [1, 2, 3, 4, 5, 6],
+        Some(-60),
+        Ipv4Addr::new(10, 0, 0, 5),
+    );
+    assert!(status.state.is_connected());
+    assert_eq!(
+        status.stats,
+        ConnectionStats {
+            failures: 1,
+            consecutive_failures: 0,
+            reconnects: 0
+        }
+    );
+    assert_eq!(
+        status.to_string(),
+        "Wi-Fi connected to Home (01:02:03:04:05:06, -60 dBm) as 10.0.0.5, 0 reconnects, 1 failed attempts"
+    );
+
+    status.disconnected(DisconnectReason::LinkLost, Some(WifiReason(200)));
+    assert_eq!(
+        status.state,
+        ConnectionState::Disconnected {
+            reason: DisconnectReason::LinkLost,
+            wifi_reason: Some(WifiReason(200))
+        }
+    );
+    assert_eq!(WifiReason(99).to_string(), "reason 99");
+    status.connecting("Home");
+    status.got_ip("Home", [1, 2, 3, 4, 5, 6], None, Ipv4Addr::new(10, 0, 0, 5));
+    assert_eq!(status.stats.reconnects, 1);
+}
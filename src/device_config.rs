@@ -0,0 +1,552 @@
+//! Per-device network settings, provisioned into NVS instead of compiled into the firmware.
+//!
+//! Every field is a separate NVS string in the [`NAMESPACE`] namespace, so the configuration can
+//! be written with the nvs_partition_gen tool from ESP-IDF, see `device_config.csv.example` and
+//! `make provision`. Without it, the firmware starts a setup portal where it can be entered, see
+//! [`crate::portal`].
+use std::net::Ipv4Addr;
+
+/// NVS namespace of the device configuration
+pub const NAMESPACE: &str = "device";
+
+pub const KEY_WIFI_SSID: &str = "wifi_ssid";
+pub const KEY_WIFI_PASSWORD: &str = "wifi_pass";
+/// `0` turns off switching to a stronger access point while connected
+pub const KEY_WIFI_ROAMING: &str = "wifi_roam";
+/// Name of the station in router tables, see [`default_hostname`] for the default
+pub const KEY_HOSTNAME: &str = "hostname";
+/// Address with prefix length, like `192.168.1.50/24`. DHCP is used when it is not set.
+pub const KEY_STATIC_IP: &str = "static_ip";
+pub const KEY_GATEWAY: &str = "gateway";
+/// One or two comma separated addresses, only used with a static address
+pub const KEY_DNS: &str = "dns";
+/// Password of the setup portal's access point, see [`DeviceConfig::portal_ap_password`]
+pub const KEY_PORTAL_PASSWORD: &str = "portal_pass";
+pub const KEY_MQTT_HOST: &str = "mqtt_host";
+pub const KEY_MQTT_CLIENT_ID: &str = "mqtt_client_id";
+pub const KEY_MQTT_USERNAME: &str = "mqtt_user";
+pub const KEY_MQTT_PASSWORD: &str = "mqtt_pass";
+
+/// Most Wi-Fi networks that can be configured
+pub const MAX_WIFI_NETWORKS: usize = 4;
+
+/// SSID and password keys of each network, the first one is the main network
+pub const WIFI_NETWORK_KEYS: [(&str, &str); MAX_WIFI_NETWORKS] = [
+    (KEY_WIFI_SSID, KEY_WIFI_PASSWORD),
+    ("wifi_ssid_2", "wifi_pass_2"),
+    ("wifi_ssid_3", "wifi_pass_3"),
+    ("wifi_ssid_4", "wifi_pass_4"),
+];
+
+/// Keys of the station interface settings, which are all optional
+pub const IP_KEYS: [&str; 4] = [KEY_HOSTNAME, KEY_STATIC_IP, KEY_GATEWAY, KEY_DNS];
+
+/// Longest hostname the network interface accepts
+pub const MAX_HOSTNAME_LEN: usize = 30;
+
+/// Longest string value, including the terminating zero, the firmware reads from NVS
+pub const MAX_VALUE_LEN: usize = 128;
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    /// Empty for an open network
+    pub password: String,
+}
+
+/// Fixed address of the station interface instead of DHCP.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    /// Length of the network prefix, 24 for `255.255.255.0`
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    /// `None` uses the gateway
+    pub dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct DeviceConfig {
+    /// Known networks, at least one. When several are in range the strongest is used, and the
+    /// order decides which one is tried first when they can't be seen in a scan.
+    pub wifi_networks: Vec<WifiNetwork>,
+    /// Switch to a stronger access point of a known network when the signal gets weak
+    pub wifi_roaming: bool,
+    /// `None` uses [`default_hostname`]
+    pub hostname: Option<String>,
+    /// `None` to get the address with DHCP
+    pub static_ip: Option<StaticIp>,
+    /// `None` to protect the setup portal with the password of the main network
+    pub portal_password: Option<String>,
+    /// For example `mqtt://example.com:1883`
+    pub mqtt_host: String,
+    /// Prefix of the device id, which is followed by the MAC address
+    pub mqtt_client_id: String,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum DeviceConfigError {
+    #[error("{0} is not set")]
+    Missing(&'static str),
+    #[error("the Wi-Fi SSID must be 1 to 32 bytes long")]
+    InvalidSsid,
+    #[error("the Wi-Fi password must be empty, 8 to 63 bytes long, or 64 hex digits")]
+    InvalidWifiPassword,
+    #[error("the MQTT host must look like mqtt://host:port")]
+    InvalidMqttHost,
+    #[error("the MQTT client id must not be empty")]
+    InvalidMqttClientId,
+    #[error("{0} must be shorter than 128 bytes")]
+    TooLong(&'static str),
+    #[error("at most 4 Wi-Fi networks can be configured")]
+    TooManyNetworks,
+    #[error("{0} must be 0 or 1")]
+    InvalidFlag(&'static str),
+    #[error("the hostname must be 1 to 30 letters, digits and dashes, not starting or ending with a dash")]
+    InvalidHostname,
+    #[error("{0} must be an IPv4 address, like 192.168.1.50")]
+    InvalidAddress(&'static str),
+    #[error("the static IP address must look like 192.168.1.50/24")]
+    InvalidStaticIp,
+    #[error("{0} is only used with a static IP address")]
+    RequiresStaticIp(&'static str),
+    #[error("the setup portal password must be 8 to 63 bytes long")]
+    InvalidPortalPassword,
+    #[error("a setup portal password is needed when the main Wi-Fi network is open")]
+    PortalPasswordRequired,
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for WifiNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WifiNetwork")
+            .field("ssid", &self.ssid)
+            .finish_non_exhaustive()
+    }
+}
+
+// Keeps the passwords out of logs
+impl std::fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("wifi_networks", &self.wifi_networks)
+            .field("wifi_roaming", &self.wifi_roaming)
+            .field("hostname", &self.hostname)
+            .field("static_ip", &self.static_ip)
+            .field("mqtt_host", &self.mqtt_host)
+            .field("mqtt_client_id", &self.mqtt_client_id)
+            .field("mqtt_username", &self.mqtt_username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeviceConfig {
+    /// Settings for the Wokwi simulator, which has a fixed open network. The broker can be set
+    /// with the `WOKWI_MQTT_*` environment variables at build time.
+    pub fn wokwi() -> Self {
+        Self {
+            wifi_networks: vec![WifiNetwork {
+                ssid: "Wokwi-GUEST".to_string(),
+                password: String::new(),
+            }],
+            wifi_roaming: false,
+            hostname: None,
+            static_ip: None,
+            portal_password: Some("bedroom-lights".to_string()),
+            mqtt_host: option_env!("WOKWI_MQTT_HOST")
+                .unwrap_or("mqtt://test.mosquitto.org:1883")
+                .to_string(),
+            mqtt_client_id: "bedroom_lights".to_string(),
+            mqtt_username: option_env!("WOKWI_MQTT_USERNAME")
+                .unwrap_or_default()
+                .to_string(),
+            mqtt_password: option_env!("WOKWI_MQTT_PASSWORD")
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    /// Reads the configuration with `get`, which returns the value stored for an NVS key.
+    ///
+    /// Only the main Wi-Fi network and the MQTT host are required, and the gateway when a static
+    /// address is set.
+    pub fn load(
+        mut get: impl FnMut(&'static str) -> Option<String>,
+    ) -> Result<Self, DeviceConfigError> {
+        let mut wifi_networks = Vec::new();
+        for (ssid_key, password_key) in WIFI_NETWORK_KEYS {
+            if let Some(ssid) = get(ssid_key) {
+                wifi_networks.push(WifiNetwork {
+                    ssid,
+                    password: get(password_key).unwrap_or_default(),
+                });
+            } else if ssid_key == KEY_WIFI_SSID {
+                return Err(DeviceConfigError::Missing(KEY_WIFI_SSID));
+            }
+        }
+        let config = Self {
+            wifi_networks,
+            wifi_roaming: match get(KEY_WIFI_ROAMING).as_deref() {
+                None | Some("1") => true,
+                Some("0") => false,
+                Some(_) => return Err(DeviceConfigError::InvalidFlag(KEY_WIFI_ROAMING)),
+            },
+            hostname: get(KEY_HOSTNAME),
+            static_ip: load_static_ip(&mut get)?,
+            portal_password: get(KEY_PORTAL_PASSWORD),
+            mqtt_host: get(KEY_MQTT_HOST).ok_or(DeviceConfigError::Missing(KEY_MQTT_HOST))?,
+            mqtt_client_id: get(KEY_MQTT_CLIENT_ID).unwrap_or_else(|| "bedroom_lights".to_string()),
+            mqtt_username: get(KEY_MQTT_USERNAME).unwrap_or_default(),
+            mqtt_password: get(KEY_MQTT_PASSWORD).unwrap_or_default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The NVS keys and values to store. Keys of unused networks and unset optional settings
+    /// are left out.
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = Vec::new();
+        for ((ssid_key, password_key), network) in WIFI_NETWORK_KEYS.iter().zip(&self.wifi_networks)
+        {
+            entries.push((*ssid_key, network.ssid.clone()));
+            entries.push((*password_key, network.password.clone()));
+        }
+        entries.push((
+            KEY_WIFI_ROAMING,
+            if self.wifi_roaming { "1" } else { "0" }.to_string(),
+        ));
+        if let Some(hostname) = &self.hostname {
+            entries.push((KEY_HOSTNAME, hostname.clone()));
+        }
+        if let Some(static_ip) = &self.static_ip {
+            entries.push((
+                KEY_STATIC_IP,
+                format!("{}/{}", static_ip.ip, static_ip.prefix_len),
+            ));
+            entries.push((KEY_GATEWAY, static_ip.gateway.to_string()));
+            let dns: Vec<_> = [static_ip.dns, static_ip.secondary_dns]
+                .into_iter()
+                .flatten()
+                .map(|ip| ip.to_string())
+                .collect();
+            if !dns.is_empty() {
+                entries.push((KEY_DNS, dns.join(",")));
+            }
+        }
+        if let Some(portal_password) = &self.portal_password {
+            entries.push((KEY_PORTAL_PASSWORD, portal_password.clone()));
+        }
+        entries.extend([
+            (KEY_MQTT_HOST, self.mqtt_host.clone()),
+            (KEY_MQTT_CLIENT_ID, self.mqtt_client_id.clone()),
+            (KEY_MQTT_USERNAME, self.mqtt_username.clone()),
+            (KEY_MQTT_PASSWORD, self.mqtt_password.clone()),
+        ]);
+        entries
+    }
+
+    /// Password of the setup portal's access point once the device is configured. Only the owner
+    /// knows it, so nobody else can read or change the settings there.
+    pub fn portal_ap_password(&self) -> &str {
+        match &self.portal_password {
+            Some(password) => password,
+            None => &self.wifi_networks[0].password,
+        }
+    }
+
+    /// The configured hostname, or the default one for the device with the MAC address `mac`
+    pub fn hostname(&self, mac: &[u8; 6]) -> String {
+        self.hostname
+            .clone()
+            .unwrap_or_else(|| default_hostname(mac))
+    }
+
+    pub fn validate(&self) -> Result<(), DeviceConfigError> {
+        if self.wifi_networks.is_empty() {
+            return Err(DeviceConfigError::Missing(KEY_WIFI_SSID));
+        }
+        if self.wifi_networks.len() > MAX_WIFI_NETWORKS {
+            return Err(DeviceConfigError::TooManyNetworks);
+        }
+        for network in &self.wifi_networks {
+            if network.ssid.is_empty() || network.ssid.len() > 32 {
+                return Err(DeviceConfigError::InvalidSsid);
+            }
+            if !is_valid_wifi_password(&network.password) {
+                return Err(DeviceConfigError::InvalidWifiPassword);
+            }
+        }
+        let host = self
+            .mqtt_host
+            .strip_prefix("mqtt://")
+            .or_else(|| self.mqtt_host.strip_prefix("mqtts://"));
+        if !host.is_some_and(|h| !h.is_empty() && !h.contains(char::is_whitespace)) {
+            return Err(DeviceConfigError::InvalidMqttHost);
+        }
+        if self.mqtt_client_id.trim().is_empty() {
+            return Err(DeviceConfigError::InvalidMqttClientId);
+        }
+        if self
+            .hostname
+            .as_deref()
+            .is_some_and(|h| !is_valid_hostname(h))
+        {
+            return Err(DeviceConfigError::InvalidHostname);
+        }
+        if self
+            .static_ip
+            .as_ref()
+            .is_some_and(|s| s.ip.is_unspecified() || !(1..=32).contains(&s.prefix_len))
+        {
+            return Err(DeviceConfigError::InvalidStaticIp);
+        }
+        if self
+            .portal_password
+            .as_ref()
+            .is_some_and(|p| !(8..=63).contains(&p.len()))
+        {
+            return Err(DeviceConfigError::InvalidPortalPassword);
+        }
+        match self
+            .entries()
+            .into_iter()
+            .find(|(_, value)| value.len() >= MAX_VALUE_LEN)
+        {
+            Some((key, _)) => Err(DeviceConfigError::TooLong(key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that a new configuration doesn't leave the setup portal open. Configurations stored
+    /// before this was required are still loaded, so that those devices keep connecting.
+    pub fn validate_new(&self) -> Result<(), DeviceConfigError> {
+        self.validate()?;
+        if self.portal_ap_password().is_empty() {
+            return Err(DeviceConfigError::PortalPasswordRequired);
+        }
+        Ok(())
+    }
+}
+
+/// `bedroom-lights-` followed by the last half of the MAC address, which is unique per device
+pub fn default_hostname(mac: &[u8; 6]) -> String {
+    format!("bedroom-lights-{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
+/// A WPA2 passphrase is 8 to 63 bytes, a raw key 64 hex digits
+fn is_valid_wifi_password(password: &str) -> bool {
+    match password.len() {
+        0 | 8..=63 => true,
+        64 => password.chars().all(|c| c.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+    (1..=MAX_HOSTNAME_LEN).contains(&hostname.len())
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+}
+
+fn load_static_ip(
+    get: &mut impl FnMut(&'static str) -> Option<String>,
+) -> Result<Option<StaticIp>, DeviceConfigError> {
+    let address = |key, value: &str| {
+        value
+            .trim()
+            .parse::<Ipv4Addr>()
+            .map_err(|_| DeviceConfigError::InvalidAddress(key))
+    };
+    let Some(static_ip) = get(KEY_STATIC_IP) else {
+        return match [KEY_GATEWAY, KEY_DNS]
+            .into_iter()
+            .find(|key| get(key).is_some())
+        {
+            Some(key) => Err(DeviceConfigError::RequiresStaticIp(key)),
+            None => Ok(None),
+        };
+    };
+    let (ip, prefix_len) = static_ip
+        .split_once('/')
+        .and_then(|(ip, prefix_len)| Some((ip.parse().ok()?, prefix_len.parse().ok()?)))
+        .ok_or(DeviceConfigError::InvalidStaticIp)?;
+    let gateway = address(
+        KEY_GATEWAY,
+        &get(KEY_GATEWAY).ok_or(DeviceConfigError::Missing(KEY_GATEWAY))?,
+    )?;
+    let (dns, secondary_dns) = match get(KEY_DNS) {
+        None => (None, None),
+        Some(dns) => {
+            let mut servers = dns.split(',');
+            let dns = address(KEY_DNS, servers.next().unwrap_or_default())?;
+            let secondary_dns = servers.next().map(|s| address(KEY_DNS, s)).transpose()?;
+            if servers.next().is_some() {
+                return Err(DeviceConfigError::InvalidAddress(KEY_DNS));
+            }
+            (Some(dns), secondary_dns)
+        }
+    };
+    Ok(Some(StaticIp {
+        ip,
+        prefix_len,
+        gateway,
+        dns,
+        secondary_dns,
+    }))
+}
+
+#[test]
+fn test_device_config() {
+    use std::collections::HashMap;
+
+    let mut nvs = HashMap::from([
+        (KEY_WIFI_SSID, "Home"),
+        (KEY_WIFI_PASSWORD, "correct horse"),
+        (KEY_MQTT_HOST, "mqtt://broker.local:1883"),
+        (KEY_MQTT_USERNAME, "lights"),
+    ]);
+    let load =
+        |nvs: &HashMap<&str, &str>| DeviceConfig::load(|key| nvs.get(key).map(|v| v.to_string()));
+
+    let config = load(&nvs).unwrap();
+    assert_eq!(config.wifi_networks.len(), 1);
+    assert!(config.wifi_roaming);
+    assert_eq!(config.mqtt_client_id, "bedroom_lights");
+    assert_eq!(config.mqtt_password, "");
+    assert!(!format!("{config:?}").contains("correct horse"));
+
+    // Round trip through the stored entries
+    let entries = config.entries();
+    let stored = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert_eq!(load(&stored).unwrap(), config);
+
+    // Extra networks, which may skip a slot
+    nvs.insert("wifi_ssid_3", "Cabin");
+    nvs.insert(KEY_WIFI_ROAMING, "0");
+    let config = load(&nvs).unwrap();
+    assert_eq!(
+        config
+            .wifi_networks
+            .iter()
+            .map(|n| n.ssid.as_str())
+            .collect::<Vec<_>>(),
+        ["Home", "Cabin"]
+    );
+    assert!(!config.wifi_roaming);
+    let entries = config.entries();
+    let stored = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert_eq!(load(&stored).unwrap(), config);
+    nvs.insert("wifi_pass_3", "short");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidWifiPassword));
+    nvs.remove("wifi_pass_3");
+    nvs.insert(KEY_WIFI_ROAMING, "yes");
+    assert_eq!(
+        load(&nvs),
+        Err(DeviceConfigError::InvalidFlag(KEY_WIFI_ROAMING))
+    );
+    nvs.remove(KEY_WIFI_ROAMING);
+
+    // Hostname and static address
+    assert_eq!(
+        config.hostname(&[0x24, 0x0a, 0xc4, 0x12, 0xab, 0x0f]),
+        "bedroom-lights-12ab0f"
+    );
+    nvs.insert(KEY_HOSTNAME, "lamp-1");
+    nvs.insert(KEY_STATIC_IP, "192.168.1.50/24");
+    nvs.insert(KEY_GATEWAY, "192.168.1.1");
+    let config = load(&nvs).unwrap();
+    assert_eq!(config.hostname(&[0; 6]), "lamp-1");
+    assert_eq!(
+        config.static_ip,
+        Some(StaticIp {
+            ip: Ipv4Addr::new(192, 168, 1, 50),
+            prefix_len: 24,
+            gateway: Ipv4Addr::new(192, 168, 1, 1),
+            dns: None,
+            secondary_dns: None,
+        })
+    );
+    nvs.insert(KEY_DNS, "1.1.1.1, 9.9.9.9");
+    let config = load(&nvs).unwrap();
+    let static_ip = config.static_ip.as_ref().unwrap();
+    assert_eq!(
+        (static_ip.dns, static_ip.secondary_dns),
+        (
+            Some(Ipv4Addr::new(1, 1, 1, 1)),
+            Some(Ipv4Addr::new(9, 9, 9, 9))
+        )
+    );
+    let entries = config.entries();
+    let stored = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert_eq!(load(&stored).unwrap(), config);
+    nvs.insert(KEY_DNS, "1.1.1.1,9.9.9.9,8.8.8.8");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidAddress(KEY_DNS)));
+    nvs.insert(KEY_DNS, "1.1.1.1");
+    nvs.insert(KEY_STATIC_IP, "192.168.1.50");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidStaticIp));
+    nvs.insert(KEY_STATIC_IP, "192.168.1.50/33");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidStaticIp));
+    nvs.remove(KEY_STATIC_IP);
+    assert_eq!(
+        load(&nvs),
+        Err(DeviceConfigError::RequiresStaticIp(KEY_GATEWAY))
+    );
+    nvs.remove(KEY_GATEWAY);
+    nvs.remove(KEY_DNS);
+    nvs.insert(KEY_HOSTNAME, "-lamp");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidHostname));
+    nvs.remove(KEY_HOSTNAME);
+
+    // The setup portal is protected with the Wi-Fi password, or its own one
+    let config = load(&nvs).unwrap();
+    assert_eq!(config.portal_ap_password(), "correct horse");
+    nvs.insert(KEY_PORTAL_PASSWORD, "short");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidPortalPassword));
+    nvs.insert(KEY_PORTAL_PASSWORD, "setup password");
+    let config = load(&nvs).unwrap();
+    assert_eq!(config.portal_ap_password(), "setup password");
+    let entries = config.entries();
+    let stored = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert_eq!(load(&stored).unwrap(), config);
+    nvs.remove(KEY_PORTAL_PASSWORD);
+    nvs.insert(KEY_WIFI_PASSWORD, "");
+    // An open network without a portal password can't be set up anymore, but a device that
+    // already has such a configuration keeps working
+    let config = load(&nvs).unwrap();
+    assert_eq!(
+        config.validate_new(),
+        Err(DeviceConfigError::PortalPasswordRequired)
+    );
+    assert_eq!(config.portal_ap_password(), "");
+    nvs.insert(KEY_WIFI_PASSWORD, "correct horse");
+    assert_eq!(load(&nvs).unwrap().validate_new(), Ok(()));
+
+    nvs.insert(KEY_WIFI_PASSWORD, "short");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidWifiPassword));
+    // The length is in bytes, which is what fits in the driver configuration
+    let umlauts = "ä".repeat(40);
+    nvs.insert(KEY_WIFI_PASSWORD, &umlauts);
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidWifiPassword));
+    nvs.insert(KEY_WIFI_PASSWORD, &umlauts[..62]);
+    assert!(load(&nvs).is_ok());
+    let key = "0123456789abcdef".repeat(4);
+    nvs.insert(KEY_WIFI_PASSWORD, &key);
+    assert!(load(&nvs).is_ok());
+    let not_hex = "x".repeat(64);
+    nvs.insert(KEY_WIFI_PASSWORD, &not_hex);
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidWifiPassword));
+    nvs.insert(KEY_WIFI_PASSWORD, "");
+    nvs.insert(KEY_MQTT_HOST, "broker.local");
+    assert_eq!(load(&nvs), Err(DeviceConfigError::InvalidMqttHost));
+    nvs.remove(KEY_WIFI_SSID);
+    assert_eq!(load(&nvs), Err(DeviceConfigError::Missing(KEY_WIFI_SSID)));
+
+    assert_eq!(DeviceConfig::wokwi().validate_new(), Ok(()));
+}
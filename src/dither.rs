@@ -0,0 +1,57 @@
+/// Error accumulating temporal dither for a single PWM channel.
+///
+/// Every update outputs one of the two integer duties surrounding the requested duty and carries
+/// the rounding error over to the next update, so the mean duty over time is exact instead of
+/// being limited to a fixed number of sub-LSB steps. For a constant duty this is a first order
+/// sigma-delta modulator, which spaces the extra pulses as evenly as possible and therefore has
+/// the shortest possible flicker period for a given duty.
+#[derive(Default, Debug, Clone)]
+pub struct TemporalDither {
+    error: f64,
+}
+
+impl TemporalDither {
+    /// Returns the raw duty to output for this update. `duty` is in units of the least significant bit.
+    pub fn next(&mut self, duty: f64, max_duty: u32) -> u32 {
+        let target = duty.clamp(0.0, max_duty as f64) + self.error;
+        let output = target.floor().min(max_duty as f64);
+        self.error = target - output;
+        output as u32
+    }
+}
+
+#[test]
+fn test_temporal_dither() {
+    const UPDATES: usize = 4096;
+    const STEPS_PER_LSB: u32 = 64;
+
+    for step in 0..16 * STEPS_PER_LSB {
+        let duty = step as f64 / STEPS_PER_LSB as f64;
+        let mut dither = TemporalDither::default();
+        let outputs: Vec<u32> = (0..UPDATES).map(|_| dither.next(duty, 1 << 17)).collect();
+
+        let mean = outputs.iter().sum::<u32>() as f64 / UPDATES as f64;
+        assert!(
+            (mean - duty).abs() <= 1.0 / UPDATES as f64,
+            "duty {duty} has mean {mean}"
+        );
+
+        // Longest run of updates without the output changing. A duty with fraction f needs at least
+        // 1/min(f, 1-f) updates per period to have the right mean.
+        let longest_run = outputs
+            .chunk_by(|a, b| a == b)
+            .map(|run| run.len())
+            .max()
+            .unwrap();
+        let fraction = duty.fract();
+        if fraction == 0.0 {
+            assert_eq!(longest_run, UPDATES);
+        } else {
+            let shortest_period = (1.0 / fraction.min(1.0 - fraction)).ceil() as usize;
+            assert!(
+                longest_run <= shortest_period,
+                "duty {duty} flickers with a period of {longest_run} updates"
+            );
+        }
+    }
+}
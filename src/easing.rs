@@ -0,0 +1,36 @@
+/// Shapes the progress `t` (0..1) of an animation segment.
+#[derive(
+    PartialEq, Eq, Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    /// Quadratic, starts slow
+    EaseIn,
+    /// Quadratic, ends slow
+    EaseOut,
+    /// Smoothstep
+    EaseInOut,
+    /// Holds the start value until the end of the segment
+    Step,
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+            Easing::Step => {
+                if t >= 1.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use crate::transition::mix_perceptual;
+
+/// How the scene color is spread over the pixels of the addressable strip.
+///
+/// Colors are `[r, g, b, w]` in the range 0..255, and durations are in milliseconds.
+#[derive(PartialEq, Eq, Debug, Clone, Default, serde::Serialize, serde::Deserialize, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    /// Every pixel shows the scene color
+    #[default]
+    Solid,
+    /// Static gradient from the scene color at the first pixel to `to` at the last
+    Gradient { to: [u32; 4] },
+    /// Color changes travel along the strip and take `duration_ms` to reach the other end.
+    /// Makes the sunrise spread from one end of the bed.
+    Sweep {
+        duration_ms: u32,
+        #[serde(default)]
+        reverse: bool,
+    },
+    /// Slowly dims by up to `depth` percent and back again
+    Breathing { period_ms: u32, depth: u32 },
+    /// A head of `length` pixels with a fading tail running along the strip once per period
+    Comet {
+        period_ms: u32,
+        length: u32,
+        #[serde(default)]
+        reverse: bool,
+    },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum EffectError {
+    #[error("durations and periods must be greater than zero")]
+    ZeroDuration,
+    #[error("the breathing depth must be between 0 and 100 percent")]
+    InvalidDepth,
+    #[error("the comet must be at least one pixel long")]
+    ZeroLength,
+    #[error("color channel {channel} is out of range (0..255)")]
+    ChannelOutOfRange { channel: usize },
+}
+
+impl Effect {
+    pub fn validate(&self) -> Result<(), EffectError> {
+        match *self {
+            Effect::Solid => {}
+            Effect::Gradient { to } => {
+                if let Some(channel) = to.iter().position(|&c| c > 255) {
+                    return Err(EffectError::ChannelOutOfRange { channel });
+                }
+            }
+            Effect::Sweep { duration_ms, .. } => {
+                if duration_ms == 0 {
+                    return Err(EffectError::ZeroDuration);
+                }
+            }
+            Effect::Breathing { period_ms, depth } => {
+                if period_ms == 0 {
+                    return Err(EffectError::ZeroDuration);
+                }
+                if depth > 100 {
+                    return Err(EffectError::InvalidDepth);
+                }
+            }
+            Effect::Comet {
+                period_ms, length, ..
+            } => {
+                if period_ms == 0 {
+                    return Err(EffectError::ZeroDuration);
+                }
+                if length == 0 {
+                    return Err(EffectError::ZeroLength);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn scale(color: [f32; 4], factor: f32) -> [f32; 4] {
+    color.map(|c| c * factor)
+}
+
+/// Fraction (0..1) of the way through the current period
+fn phase(t: Duration, period_ms: u32) -> f32 {
+    (t.as_millis() % period_ms as u128) as f32 / period_ms as f32
+}
+
+/// Renders frames of an [`Effect`]. Should be called at a fixed frame rate.
+#[derive(Debug, Clone, Default)]
+pub struct EffectRenderer {
+    effect: Effect,
+    /// Earlier scene colors for [`Effect::Sweep`], newest first, one per pixel
+    history: VecDeque<[f32; 4]>,
+    last_sample: Option<Duration>,
+}
+
+impl EffectRenderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fills `frame` with the color (0..255) of every pixel. `base` is the scene color and `t` is
+    /// the time since some fixed point, e.g. when the renderer was created.
+    pub fn render(&mut self, effect: &Effect, base: [f32; 4], t: Duration, frame: &mut [[f32; 4]]) {
+        if *effect != self.effect {
+            self.effect = effect.clone();
+            self.history.clear();
+            self.last_sample = None;
+        }
+
+        let n = frame.len();
+        // Position of a pixel along the strip, 0 at the first pixel and 1 at the last
+        let position = |i: usize| {
+            if n > 1 {
+                i as f32 / (n - 1) as f32
+            } else {
+                0.0
+            }
+        };
+
+        match *effect {
+            Effect::Solid => frame.fill(base),
+            Effect::Gradient { to } => {
+                let to = to.map(|c| c as f32);
+                for (i, pixel) in frame.iter_mut().enumerate() {
+                    *pixel = mix_perceptual(base, to, position(i));
+                }
+            }
+            Effect::Sweep {
+                duration_ms,
+                reverse,
+            } => {
+                let interval = Duration::from_millis(duration_ms as u64) / n.max(1) as u32;
+                let due = match self.last_sample {
+                    Some(last) => t.saturating_sub(last) >= interval,
+                    None => true,
+                };
+                if due {
+                    self.history.push_front(base);
+                    self.history.truncate(n);
+                    self.last_sample = Some(t);
+                }
+                for (i, pixel) in frame.iter_mut().enumerate() {
+                    let i = if reverse { n - 1 - i } else { i };
+                    *pixel = if i == 0 {
+                        base
+                    } else {
+                        *self.history.get(i).or(self.history.back()).unwrap()
+                    };
+                }
+            }
+            Effect::Breathing { period_ms, depth } => {
+                let dip = 0.5 - 0.5 * (TAU * phase(t, period_ms)).cos();
+                frame.fill(scale(base, 1.0 - depth as f32 / 100.0 * dip));
+            }
+            Effect::Comet {
+                period_ms,
+                length,
+                reverse,
+            } => {
+                // The head runs past the end until the whole tail has left the strip
+                let length = length as f32;
+                let head = phase(t, period_ms) * (n as f32 + length) - 1.0;
+                for (i, pixel) in frame.iter_mut().enumerate() {
+                    let i = if reverse { n - 1 - i } else { i };
+                    let behind = head - i as f32;
+                    let brightness = if behind < 0.0 {
+                        // Anti-aliased front edge
+                        (1.0 + behind).max(0.0)
+                    } else {
+                        (1.0 - behind / length).max(0.0)
+                    };
+                    *pixel = scale(base, brightness);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_effects() {
+    let base = [200.0, 100.0, 50.0, 10.0];
+    let ms = Duration::from_millis;
+    let mut renderer = EffectRenderer::new();
+    let mut frame = [[0.0; 4]; 10];
+
+    renderer.render(&Effect::Solid, base, ms(0), &mut frame);
+    assert!(frame.iter().all(|&p| p == base));
+
+    let gradient = Effect::Gradient { to: [0; 4] };
+    renderer.render(&gradient, base, ms(0), &mut frame);
+    assert_eq!(frame[0], base);
+    assert_eq!(frame[9], [0.0; 4]);
+
+    let breathing: Effect =
+        serde_json::from_str(r#"{"type": "breathing", "period_ms": 4000, "depth": 50}"#).unwrap();
+    assert_eq!(breathing.validate(), Ok(()));
+    renderer.render(&breathing, base, ms(0), &mut frame);
+    assert_eq!(frame[0], base);
+    renderer.render(&breathing, base, ms(2000), &mut frame);
+    assert_eq!(frame[5], scale(base, 0.5));
+
+    // The head enters at the start of the period and has left when it wraps around
+    let comet = Effect::Comet {
+        period_ms: 1400,
+        length: 4,
+        reverse: false,
+    };
+    renderer.render(&comet, base, ms(0), &mut frame);
+    assert!(frame.iter().all(|&p| p == [0.0; 4]));
+    renderer.render(&comet, base, ms(600), &mut frame);
+    assert_eq!(frame[5], base);
+    assert_eq!(frame[4], scale(base, 0.75));
+    assert_eq!(frame[1], [0.0; 4]);
+    assert_eq!(frame[6], [0.0; 4]);
+
+    // A color change takes the sweep duration to reach the far end
+    let sweep = Effect::Sweep {
+        duration_ms: 1000,
+        reverse: false,
+    };
+    let off = [0.0; 4];
+    for t in (0..1000).step_by(10) {
+        renderer.render(&sweep, off, ms(t), &mut frame);
+    }
+    for t in (1000..1500).step_by(10) {
+        renderer.render(&sweep, base, ms(t), &mut frame);
+    }
+    assert_eq!(frame[0], base);
+    assert_eq!(frame[4], base);
+    assert_eq!(frame[6], off);
+    for t in (1500..2000).step_by(10) {
+        renderer.render(&sweep, base, ms(t), &mut frame);
+    }
+    assert!(frame.iter().all(|&p| p == base));
+
+    assert_eq!(
+        Effect::Breathing {
+            period_ms: 0,
+            depth: 10
+        }
+        .validate(),
+        Err(EffectError::ZeroDuration)
+    );
+}
@@ -0,0 +1,146 @@
+use crate::color::RGBWColor;
+
+/// What turning the knob does.
+#[derive(
+    PartialEq, Eq, Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum KnobMode {
+    /// The knob is only published over MQTT. The default, since a board without a knob reads
+    /// arbitrary values.
+    #[default]
+    Disabled,
+    /// Dims the output like a regular dimmer
+    Brightness,
+    /// Turning the knob sets a manual override with the knob as the brightness, which lasts until
+    /// the scene changes
+    Override,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct KnobConfig {
+    pub mode: KnobMode,
+    /// Color of the override in [`KnobMode::Override`]
+    #[serde(default = "default_override_color")]
+    pub override_color: RGBWColor,
+}
+
+fn default_override_color() -> RGBWColor {
+    "2700K".parse().unwrap()
+}
+
+impl Default for KnobConfig {
+    fn default() -> Self {
+        Self {
+            mode: KnobMode::default(),
+            override_color: default_override_color(),
+        }
+    }
+}
+
+/// Turns noisy ADC samples of a potentiometer into a stable knob position.
+///
+/// The samples are smoothed with an exponential moving average, and the position only follows
+/// the smoothed value once it has moved further than the hysteresis, so noise never looks like
+/// the knob being turned.
+#[derive(Debug, Clone)]
+pub struct KnobFilter {
+    /// Weight (0..1) of a new sample in the moving average
+    smoothing: f32,
+    hysteresis: f32,
+    filtered: Option<f32>,
+    position: f32,
+}
+
+impl KnobFilter {
+    pub fn new(smoothing: f32, hysteresis: f32) -> Self {
+        Self {
+            smoothing,
+            hysteresis,
+            filtered: None,
+            position: 0.0,
+        }
+    }
+
+    /// Position in the range 0..1
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    /// Feeds a new sample (0..1). Returns the new position if the knob was moved.
+    ///
+    /// The first sample only sets the initial position, so the knob is not reported as moved at boot.
+    pub fn update(&mut self, sample: f32) -> Option<f32> {
+        let sample = sample.clamp(0.0, 1.0);
+        let Some(filtered) = self.filtered else {
+            self.filtered = Some(sample);
+            self.position = self.snap(sample);
+            return None;
+        };
+        let filtered = filtered + (sample - filtered) * self.smoothing;
+        self.filtered = Some(filtered);
+
+        if (filtered - self.position).abs() <= self.hysteresis {
+            return None;
+        }
+        let position = self.snap(filtered);
+        if position == self.position {
+            return None;
+        }
+        self.position = position;
+        Some(position)
+    }
+
+    /// Rounds values near the ends to the ends, which the hysteresis would otherwise keep the
+    /// position from reaching
+    fn snap(&self, value: f32) -> f32 {
+        if value < self.hysteresis {
+            0.0
+        } else if value > 1.0 - self.hysteresis {
+            1.0
+        } else {
+            value
+        }
+    }
+}
+
+impl Default for KnobFilter {
+    fn default() -> Self {
+        Self::new(0.3, 0.02)
+    }
+}
+
+#[test]
+fn test_knob_filter() {
+    let mut knob = KnobFilter::default();
+    assert_eq!(knob.update(0.5), None);
+    assert_eq!(knob.position(), 0.5);
+
+    // Noise of a few percent never moves the knob
+    let noise = [0.51, 0.49, 0.53, 0.48, 0.52, 0.5, 0.47, 0.53, 0.5, 0.51];
+    for _ in 0..10 {
+        for sample in noise {
+            assert_eq!(knob.update(sample), None);
+        }
+    }
+
+    // A real turn is reported after a few samples, and then settles
+    let moves: Vec<f32> = (0..20).filter_map(|_| knob.update(0.8)).collect();
+    assert!(!moves.is_empty() && moves.len() < 10, "{moves:?}");
+    assert!((knob.position() - 0.8).abs() <= 0.02);
+    assert!((0..20).all(|_| knob.update(0.8).is_none()));
+
+    // The ends can be reached even with hysteresis
+    for _ in 0..50 {
+        knob.update(0.0);
+    }
+    assert_eq!(knob.position(), 0.0);
+    for _ in 0..50 {
+        knob.update(1.0);
+    }
+    assert_eq!(knob.position(), 1.0);
+
+    let config: KnobConfig = serde_json::from_str(r#"{"mode": "override"}"#).unwrap();
+    assert_eq!(config.mode, KnobMode::Override);
+    assert_eq!(config.override_color, "2700K".parse().unwrap());
+}
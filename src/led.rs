@@ -0,0 +1,292 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+// Tokio's clock, so that the animations can run with paused time in tests
+use tokio::time::Instant;
+
+use crate::dither::TemporalDither;
+use crate::pwm::PwmChannel;
+
+/// Lock-free setpoint of a [`DebugLed`], written by the main loop and read by the timer callback.
+pub struct DebugLedDithered {
+    desired_intensity: AtomicU32,
+    current_intensity: AtomicU32,
+    smoothing_factor: f32,
+    resolution: u32,
+}
+
+impl DebugLedDithered {
+    pub fn resolution(&self) -> u32 {
+        self.resolution
+    }
+
+    pub fn set_intensity(&self, duty: f32) {
+        self.desired_intensity
+            .store(duty.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_intensity(&self) -> f32 {
+        f32::from_bits(self.desired_intensity.load(Ordering::Relaxed))
+    }
+
+    pub fn update_smoothing(&self, dt_secs: f32) -> f32 {
+        let current = f32::from_bits(self.current_intensity.load(Ordering::Relaxed));
+        let desired = f32::from_bits(self.desired_intensity.load(Ordering::Relaxed));
+        let alpha = self.smoothing_factor * dt_secs;
+        let new_intensity = current + (desired - current) * alpha.clamp(0.0, 1.0);
+        self.current_intensity
+            .store(new_intensity.to_bits(), Ordering::Relaxed);
+        new_intensity
+    }
+}
+
+pub struct DebugLed<P> {
+    driver: P,
+    dither: TemporalDither,
+}
+
+impl<P: PwmChannel> DebugLed<P> {
+    pub fn new(driver: P) -> Self {
+        Self {
+            driver,
+            dither: TemporalDither::default(),
+        }
+    }
+
+    pub fn driver(&self) -> &P {
+        &self.driver
+    }
+
+    pub fn resolution(&self) -> u32 {
+        self.driver.max_duty()
+    }
+
+    pub fn set_duty_raw(&mut self, duty: u32) -> Result<(), P::Error> {
+        self.driver.set_duty_raw(duty)
+    }
+
+    pub fn set_duty(&mut self, duty: f32) -> Result<(), P::Error> {
+        self.set_duty_raw((duty * self.driver.max_duty() as f32).round() as u32)
+    }
+
+    /// Should be called at a fixed rate. The mean duty over time will match the requested duty,
+    /// even for duties between two representable levels.
+    pub fn set_duty_dithered(&mut self, duty: f32) -> Result<(), P::Error> {
+        let max_duty = self.driver.max_duty();
+        let raw = self.dither.next(duty as f64 * max_duty as f64, max_duty);
+        self.set_duty_raw(raw)
+    }
+
+    pub async fn blink(&mut self, times: usize, period: Duration) -> Result<(), P::Error> {
+        for _ in 0..times {
+            self.set_duty(1.0)?;
+            tokio::time::sleep(period).await;
+            self.set_duty(0.0)?;
+            tokio::time::sleep(period).await;
+        }
+        Ok(())
+    }
+
+    pub fn to_dithered(&self) -> DebugLedDithered {
+        DebugLedDithered {
+            desired_intensity: AtomicU32::new(0f32.to_bits()),
+            current_intensity: AtomicU32::new(0f32.to_bits()),
+            // Only smooths out the steps between main loop updates, fades are handled by the Crossfade
+            smoothing_factor: 20.0,
+            resolution: self.resolution(),
+        }
+    }
+}
+
+pub async fn blink_strips<P: PwmChannel>(power_levels: &mut [DebugLed<P>]) -> Result<(), P::Error> {
+    let up_dur = Duration::from_millis(100);
+    let down_dur = Duration::from_millis(200);
+    let stagger_dur = Duration::from_millis(60);
+
+    fn eval_local(t: f32, up: f32, down: f32) -> f32 {
+        // t in seconds, up/down in seconds
+        let r = if t <= 0.0 {
+            0.0
+        } else if t < up {
+            // ease-in (quadratic)
+            t / up
+            // let x = t / up;
+            // (x * x).clamp(0.0, 1.0)
+        } else if t < up + down {
+            // ease-out (quadratic)
+            let x = (t - up) / down;
+            // (1.0 - x * x).clamp(0.0, 1.0)
+            (1.0 - x).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        r * r
+    }
+
+    let n = power_levels.len();
+    let total_secs = (stagger_dur.as_secs_f32() * (n.saturating_sub(1) as f32))
+        + up_dur.as_secs_f32()
+        + down_dur.as_secs_f32();
+
+    let start = Instant::now();
+    loop {
+        let elapsed = start.elapsed().as_secs_f32();
+
+        // stop once the whole staggered sequence finished
+        if elapsed > total_secs {
+            break;
+        }
+
+        for (i, pl) in power_levels.iter_mut().enumerate() {
+            let offset = stagger_dur.as_secs_f32() * (i as f32);
+            let local_t = elapsed - offset;
+            let v = eval_local(local_t, up_dur.as_secs_f32(), down_dur.as_secs_f32());
+            pl.set_duty(v * 0.1)?;
+        }
+
+        // update at ~10ms intervals to keep the ramps smooth
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // ensure all are off at the end
+    for pl in power_levels.iter_mut() {
+        pl.set_duty(0.0)?;
+    }
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    for p in 0..10 {
+        for pl in power_levels.iter_mut() {
+            pl.set_duty_raw(p)?;
+        }
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+    for p in 0..10 {
+        for pl in power_levels.iter_mut() {
+            pl.set_duty_raw(9 - p)?;
+        }
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+
+    let t0 = Instant::now();
+    loop {
+        let elapsed = t0.elapsed().as_secs_f32();
+        if elapsed > 20.0 {
+            break;
+        }
+        let v = if elapsed < 10.0 {
+            (elapsed / 10.0) * 9.0
+        } else {
+            ((20.0 - elapsed) / 10.0) * 9.0
+        };
+        for pl in power_levels.iter_mut() {
+            pl.set_duty_dithered(v / (pl.resolution() as f32))?;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    Ok(())
+}
+
+pub async fn blink_strips_d(power_levels: &[DebugLedDithered]) {
+    let up_dur = Duration::from_millis(100);
+    let down_dur = Duration::from_millis(200);
+    let stagger_dur = Duration::from_millis(60);
+
+    fn eval_local(t: f32, up: f32, down: f32) -> f32 {
+        // t in seconds, up/down in seconds
+        let r = if t <= 0.0 {
+            0.0
+        } else if t < up {
+            // ease-in (quadratic)
+            t / up
+            // let x = t / up;
+            // (x * x).clamp(0.0, 1.0)
+        } else if t < up + down {
+            // ease-out (quadratic)
+            let x = (t - up) / down;
+            // (1.0 - x * x).clamp(0.0, 1.0)
+            (1.0 - x).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        r * r
+    }
+
+    let n = power_levels.len();
+    let total_secs = (stagger_dur.as_secs_f32() * (n.saturating_sub(1) as f32))
+        + up_dur.as_secs_f32()
+        + down_dur.as_secs_f32();
+
+    let start = Instant::now();
+    loop {
+        let elapsed = start.elapsed().as_secs_f32();
+
+        // stop once the whole staggered sequence finished
+        if elapsed > total_secs {
+            break;
+        }
+
+        for (i, pl) in power_levels.iter().enumerate() {
+            let offset = stagger_dur.as_secs_f32() * (i as f32);
+            let local_t = elapsed - offset;
+            let v = eval_local(local_t, up_dur.as_secs_f32(), down_dur.as_secs_f32());
+            pl.set_intensity(v * 0.05);
+        }
+
+        // update at ~10ms intervals to keep the ramps smooth
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // ensure all are off at the end
+    for pl in power_levels.iter() {
+        pl.set_intensity(0.0);
+    }
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+
+    let t0 = Instant::now();
+    loop {
+        let elapsed = t0.elapsed().as_secs_f32();
+        if elapsed > 20.0 {
+            break;
+        }
+        let v = if elapsed < 10.0 {
+            (elapsed / 10.0) * 9.0
+        } else {
+            ((20.0 - elapsed) / 10.0) * 9.0
+        };
+        for pl in power_levels.iter() {
+            pl.set_intensity(v / (pl.resolution() as f32));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_debug_led() {
+    use crate::pwm::RecordingPwm;
+
+    let mut led = DebugLed::new(RecordingPwm::new(1000));
+    led.blink(2, Duration::from_millis(50)).await.unwrap();
+    assert_eq!(led.driver().duties, [1000, 0, 1000, 0]);
+
+    let mut led = DebugLed::new(RecordingPwm::new(1024));
+    for _ in 0..100 {
+        led.set_duty_dithered(12.25 / 1024.0).unwrap();
+    }
+    let duties = &led.driver().duties;
+    assert!(duties.iter().all(|&d| d == 12 || d == 13));
+    assert_eq!(duties.iter().sum::<u32>(), 1225);
+
+    let mut strips = [
+        DebugLed::new(RecordingPwm::new(1 << 17)),
+        DebugLed::new(RecordingPwm::new(1 << 17)),
+    ];
+    blink_strips(&mut strips).await.unwrap();
+    for strip in &strips {
+        let duties = &strip.driver().duties;
+        assert!(duties.iter().all(|&d| d <= (1 << 17) / 10));
+        assert_eq!(duties.last(), Some(&0));
+    }
+}
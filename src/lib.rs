@@ -0,0 +1,27 @@
+//! Hardware independent parts of the bedroom lights firmware.
+//!
+//! Everything in here builds on the host, so the lighting policy can be tested with
+//! `cargo +stable test --lib --no-default-features`.
+pub mod button;
+pub mod calibration;
+pub mod clock;
+pub mod color;
+pub mod connection;
+pub mod device_config;
+pub mod dither;
+pub mod easing;
+pub mod effects;
+pub mod knob;
+pub mod led;
+pub mod manual;
+pub mod persist;
+pub mod portal;
+pub mod pwm;
+pub mod remote;
+pub mod roaming;
+pub mod scene;
+pub mod schedule;
+pub mod strip;
+pub mod sunrise;
+pub mod timezone;
+pub mod transition;
@@ -1,14 +1,33 @@
 #![deny(clippy::future_not_send)]
 mod esp;
+mod provisioning;
 mod wifi;
 mod wokwi;
 
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use bedroom_lights3::button::{ButtonAction, ButtonConfig, Gesture, GestureDetector};
+use bedroom_lights3::calibration::{OutputCalibration, OutputLimits};
+use bedroom_lights3::clock::{SavedClock, TimeQuality, TimeSource};
+use bedroom_lights3::color::{RGBWColor, SceneLight};
+use bedroom_lights3::connection::WifiStatus;
+use bedroom_lights3::device_config::{self, DeviceConfig};
+use bedroom_lights3::effects::{Effect, EffectRenderer};
+use bedroom_lights3::knob::{KnobConfig, KnobFilter, KnobMode};
+use bedroom_lights3::led::{DebugLed, DebugLedDithered};
+use bedroom_lights3::manual::{ManualOverride, OverrideExpiry};
+use bedroom_lights3::persist::{self, WriteLimiter};
+use bedroom_lights3::remote::RemoteState;
+use bedroom_lights3::scene::{OverrideEvent, SceneColors, SceneEngine, SceneEvent, SceneReason};
+use bedroom_lights3::schedule::{Scene, Schedule};
+use bedroom_lights3::strip::{OutputConfig, PixelStrip, StripConfig, Ws2812Output};
+use bedroom_lights3::sunrise::SunriseCurve;
+use bedroom_lights3::timezone::{Timezone, DEFAULT_TIMEZONE};
+use bedroom_lights3::transition::{Crossfade, TransitionConfig};
 use brevduva::{channel::SerializationFormat, ReadWriteMode, SyncStorage};
-use chrono::{DateTime, FixedOffset, Timelike, Utc};
+use chrono::Utc;
 use esp::init_esp;
 use esp_idf_svc::hal::reset::ResetReason;
 use esp_idf_svc::handle::RawHandle;
@@ -19,26 +38,38 @@ use esp_idf_svc::{
         adc::{
             attenuation::DB_11,
             oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+            ADC1,
         },
+        gpio::{Gpio34, PinDriver, Pull},
         ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
+        modem::Modem,
         prelude::*,
     },
-    nvs::EspDefaultNvsPartition,
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
     ota::EspOta,
-    sntp::EspSntp,
+    sntp::{EspSntp, SntpConf},
     sys::EspError,
+    timer::EspTaskTimerService,
 };
-use log::{info, warn};
-use smart_leds::RGB8;
+use log::{error, info, warn};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver};
 use wifi::start_wifi;
 use wokwi::check_is_wokwi;
-// use ws2812_esp32_rmt_driver::driver::color::LedPixelColorGrb24;
-// use ws2812_esp32_rmt_driver::LedPixelEsp32Rmt;
-
-const MQTT_HOST: &str = "mqtt://arongranberg.com:1883";
-const MQTT_CLIENT_ID: &str = "bedroom_lights";
-const MQTT_USERNAME: &str = "wakeup_alarm";
-const MQTT_PASSWORD: &str = "xafzz25nomehasff";
+use ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriver;
+
+/// Highest raw reading of the 12 bit ADC
+const ADC_MAX: f32 = 4095.0;
+
+/// What the addressable strip shows, published by the main loop to the strip thread.
+#[derive(Clone, Default)]
+struct StripState {
+    config: StripConfig,
+    effect: Effect,
+    /// Scene color (0..255), or `None` when the strip is not the selected output
+    color: Option<[f32; 4]>,
+    /// Factor from the [`OutputLimits`]
+    limit: f32,
+}
 
 struct Logger {}
 
@@ -78,373 +109,11 @@ fn main() {
         .unwrap();
 }
 
-struct DebugLedDithered {
-    desired_intensity: AtomicU32,
-    current_intensity: AtomicU32,
-    smoothing_factor: f32,
-    resolution: u32,
-}
-
-impl DebugLedDithered {
-    pub fn resolution(&self) -> u32 {
-        self.resolution
-    }
-
-    fn set_intensity(&self, duty: f32) -> Result<(), EspError> {
-        self.desired_intensity
-            .store(duty.to_bits(), Ordering::Relaxed);
-        Ok(())
-    }
-
-    fn get_intensity(&self) -> f32 {
-        f32::from_bits(self.desired_intensity.load(Ordering::Relaxed))
-    }
-
-    fn update_smoothing(&self, dt_secs: f32) -> f32 {
-        let current = f32::from_bits(self.current_intensity.load(Ordering::Relaxed));
-        let desired = f32::from_bits(self.desired_intensity.load(Ordering::Relaxed));
-        let alpha = self.smoothing_factor * dt_secs;
-        let new_intensity = current + (desired - current) * alpha.clamp(0.0, 1.0);
-        self.current_intensity
-            .store(new_intensity.to_bits(), Ordering::Relaxed);
-        new_intensity
-    }
-}
-
-struct DebugLed {
-    driver: LedcDriver<'static>,
-}
-
-impl DebugLed {
-    fn new(driver: LedcDriver<'static>) -> Self {
-        Self { driver }
-    }
-
-    pub fn resolution(&self) -> u32 {
-        self.driver.get_max_duty()
-    }
-
-    fn set_duty_raw(&mut self, duty: u32) -> Result<(), EspError> {
-        self.driver.set_duty(duty)
-    }
-
-    fn set_duty(&mut self, duty: f32) -> Result<(), EspError> {
-        self.set_duty_raw((duty * self.driver.get_max_duty() as f32).round() as u32)
-    }
-
-    fn set_duty_dithered(&mut self, duty: f32, time_index: usize) -> Result<(), EspError> {
-        self.set_duty_raw(
-            ((duty as f64) * self.driver.get_max_duty() as f64
-            // (((time_index % 500) as f32 / 500.0)
-                + (DITHER[time_index % DITHER.len()] as f64 * (1.0/16.0)))
-                .floor() as u32,
-        )
-    }
-
-    async fn blink(&mut self, times: usize, period: Duration) -> Result<(), EspError> {
-        for _ in 0..times {
-            self.set_duty(1.0)?;
-            tokio::time::sleep(period).await;
-            self.set_duty(0.0)?;
-            tokio::time::sleep(period).await;
-        }
-        Ok(())
-    }
-
-    pub fn to_dithered(&self) -> DebugLedDithered {
-        DebugLedDithered {
-            desired_intensity: AtomicU32::new(0f32.to_bits()),
-            current_intensity: AtomicU32::new(0f32.to_bits()),
-            smoothing_factor: 0.2,
-            resolution: self.resolution(),
-        }
-    }
-}
-
 fn successful_boot() {
     let mut ota = EspOta::new().expect("obtain OTA instance");
     ota.mark_running_slot_valid().expect("mark app as valid");
 }
 
-const SUNRISE_ANIMATION_RGBW: &[(f32, [f32; 4])] = &[
-    (0.0f32, [0.0, 0.0, 0.0, 0.0]),
-    (1.0 * 60.0, [255.0, 70.0, 0.0, 0.0]),
-    (3.0 * 60.0, [255.0, 87.0, 0.0, 87.0]),
-    (5.0 * 60.0, [255.0, 123.0, 0.0, 123.0]),
-    (20.0 * 60.0, [255.0, 123.0, 0.0, 160.0]),
-];
-
-const SUNRISE_ANIMATION: &[(f32, [f32; 4])] = &[
-    (0.0, [0.0, 0.0, 0.0, 0.0]),
-    (1.0 * 60.0, [0.0, 0.0, 100.0, 0.0]),
-    (3.0 * 60.0, [0.0, 50.0, 140.0, 0.0]),
-    (5.0 * 60.0, [20.0, 100.0, 180.0, 0.0]),
-    (20.0 * 60.0, [150.0, 100.0, 200.0, 0.0]),
-];
-
-const DITHER: [u32; 32] = [
-    9, 3, 13, 7, 1, 10, 4, 14, 8, 2, 11, 5, 15, 9, 3, 13, 6, 0, 10, 4, 14, 7, 1, 11, 5, 15, 8, 2,
-    12, 6, 0, 10,
-];
-
-#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
-struct InnerAlarmState {
-    next_alarm: DateTime<Utc>,
-    enabled: bool,
-}
-
-#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
-struct AlarmLastPlayed {
-    last_played_time: Option<DateTime<Utc>>,
-}
-
-async fn blink_strips(power_levels: &mut [DebugLed]) -> Result<(), EspError> {
-    let up_dur = Duration::from_millis(100);
-    let down_dur = Duration::from_millis(200);
-    let stagger_dur = Duration::from_millis(60);
-
-    fn eval_local(t: f32, up: f32, down: f32) -> f32 {
-        // t in seconds, up/down in seconds
-        let r = if t <= 0.0 {
-            0.0
-        } else if t < up {
-            // ease-in (quadratic)
-            let x = t / up;
-            x
-            // (x * x).clamp(0.0, 1.0)
-        } else if t < up + down {
-            // ease-out (quadratic)
-            let x = (t - up) / down;
-            // (1.0 - x * x).clamp(0.0, 1.0)
-            (1.0 - x).clamp(0.0, 1.0)
-        } else {
-            0.0
-        };
-        r * r
-    }
-
-    let n = power_levels.len();
-    let total_secs = (stagger_dur.as_secs_f32() * (n.saturating_sub(1) as f32))
-        + up_dur.as_secs_f32()
-        + down_dur.as_secs_f32();
-
-    let start = Instant::now();
-    loop {
-        let elapsed = start.elapsed().as_secs_f32();
-
-        // stop once the whole staggered sequence finished
-        if elapsed > total_secs {
-            break;
-        }
-
-        for (i, pl) in power_levels.iter_mut().enumerate() {
-            let offset = stagger_dur.as_secs_f32() * (i as f32);
-            let local_t = elapsed - offset;
-            let v = eval_local(local_t, up_dur.as_secs_f32(), down_dur.as_secs_f32());
-            pl.set_duty(v * 0.1)?;
-        }
-
-        // update at ~10ms intervals to keep the ramps smooth
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    }
-
-    // ensure all are off at the end
-    for pl in power_levels.iter_mut() {
-        pl.set_duty(0.0)?;
-    }
-
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-
-    for p in 0..10 {
-        for pl in power_levels.iter_mut() {
-            pl.set_duty_raw(p)?;
-        }
-        tokio::time::sleep(Duration::from_millis(1000)).await;
-    }
-    for p in 0..10 {
-        for pl in power_levels.iter_mut() {
-            pl.set_duty_raw(9 - p)?;
-        }
-        tokio::time::sleep(Duration::from_millis(1000)).await;
-    }
-
-    let t0 = Instant::now();
-    for it in 0.. {
-        let elapsed = t0.elapsed().as_secs_f32();
-        if elapsed > 20.0 {
-            break;
-        }
-        let v = if elapsed < 10.0 {
-            (elapsed / 10.0) * 9.0
-        } else {
-            ((20.0 - elapsed) / 10.0) * 9.0
-        };
-        for pl in power_levels.iter_mut() {
-            pl.set_duty_dithered(v / (pl.resolution() as f32), it)?;
-        }
-        tokio::time::sleep(Duration::from_millis(1)).await;
-    }
-
-    Ok(())
-}
-
-async fn blink_strips_d(power_levels: &[DebugLedDithered]) -> Result<(), EspError> {
-    let up_dur = Duration::from_millis(100);
-    let down_dur = Duration::from_millis(200);
-    let stagger_dur = Duration::from_millis(60);
-
-    fn eval_local(t: f32, up: f32, down: f32) -> f32 {
-        // t in seconds, up/down in seconds
-        let r = if t <= 0.0 {
-            0.0
-        } else if t < up {
-            // ease-in (quadratic)
-            let x = t / up;
-            x
-            // (x * x).clamp(0.0, 1.0)
-        } else if t < up + down {
-            // ease-out (quadratic)
-            let x = (t - up) / down;
-            // (1.0 - x * x).clamp(0.0, 1.0)
-            (1.0 - x).clamp(0.0, 1.0)
-        } else {
-            0.0
-        };
-        r * r
-    }
-
-    let n = power_levels.len();
-    let total_secs = (stagger_dur.as_secs_f32() * (n.saturating_sub(1) as f32))
-        + up_dur.as_secs_f32()
-        + down_dur.as_secs_f32();
-
-    let start = Instant::now();
-    loop {
-        let elapsed = start.elapsed().as_secs_f32();
-
-        // stop once the whole staggered sequence finished
-        if elapsed > total_secs {
-            break;
-        }
-
-        for (i, pl) in power_levels.iter().enumerate() {
-            let offset = stagger_dur.as_secs_f32() * (i as f32);
-            let local_t = elapsed - offset;
-            let v = eval_local(local_t, up_dur.as_secs_f32(), down_dur.as_secs_f32());
-            pl.set_intensity(v * 0.05)?;
-        }
-
-        // update at ~10ms intervals to keep the ramps smooth
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    }
-
-    // ensure all are off at the end
-    for pl in power_levels.iter() {
-        pl.set_intensity(0.0)?;
-    }
-
-    tokio::time::sleep(Duration::from_millis(1000)).await;
-
-    let t0 = Instant::now();
-    for it in 0.. {
-        let elapsed = t0.elapsed().as_secs_f32();
-        if elapsed > 20.0 {
-            break;
-        }
-        let v = if elapsed < 10.0 {
-            (elapsed / 10.0) * 9.0
-        } else {
-            ((20.0 - elapsed) / 10.0) * 9.0
-        };
-        for pl in power_levels.iter() {
-            pl.set_intensity(v / (pl.resolution() as f32))?;
-        }
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    }
-
-    Ok(())
-}
-
-fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
-    let mut res = [0.0; 4];
-    for i in 0..4 {
-        res[i] = (a[i] + (b[i] - a[i]) * t).clamp(0.0, 255.0);
-    }
-    res
-}
-
-fn get_wakup_color(t: f32) -> [f32; 4] {
-    for i in 0..SUNRISE_ANIMATION.len() - 1 {
-        let (at, ac) = SUNRISE_ANIMATION[i];
-        let (bt, bc) = SUNRISE_ANIMATION[i + 1];
-        assert!(
-            bt > at,
-            "Animation keyframes must be in increasing time order"
-        );
-        if t >= at && t < bt {
-            return lerp(ac, bc, (t - at) / (bt - at));
-        }
-    }
-    SUNRISE_ANIMATION.last().unwrap().1
-}
-
-#[test]
-fn test_lerp() {
-    let a = [0.0, 0.0, 0.0, 0.0];
-    let b = [100.0, 200.0, 300.0, 400.0];
-    let r = lerp(a, b, 0.5);
-    assert_eq!(r, [50.0, 100.0, 150.0, 200.0]);
-    assert_eq!(lerp(a, b, 0.0), a);
-    assert_eq!(lerp(a, b, 1.0), b);
-}
-
-#[derive(PartialEq, Eq, Clone, Hash, Copy)]
-struct RGBColor {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-impl std::fmt::Debug for RGBColor {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({},{},{})", self.r, self.g, self.b)
-    }
-}
-
-impl serde::Serialize for RGBColor {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let rgb = format!("rgb({},{},{})", self.r, self.g, self.b);
-        serializer.serialize_str(&rgb)
-    }
-}
-
-impl From<RGBColor> for [f32; 4] {
-    fn from(color: RGBColor) -> Self {
-        [color.r as f32, color.g as f32, color.b as f32, 0.0]
-    }
-}
-
-impl<'de> serde::Deserialize<'de> for RGBColor {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let rgb: &str = serde::Deserialize::deserialize(deserializer)?;
-        if let Some(stripped) = rgb.strip_prefix("rgb(").and_then(|s| s.strip_suffix(')')) {
-            let parts: Vec<&str> = stripped.split(',').map(|s| s.trim()).collect();
-            if parts.len() == 3 {
-                let r = parts[0].parse::<u8>().map_err(serde::de::Error::custom)?;
-                let g = parts[1].parse::<u8>().map_err(serde::de::Error::custom)?;
-                let b = parts[2].parse::<u8>().map_err(serde::de::Error::custom)?;
-                return Ok(RGBColor { r, g, b });
-            }
-        }
-        Err(serde::de::Error::custom("Invalid rgb(r,g,b) color format"))
-    }
-}
 async fn async_main() -> Result<(), EspError> {
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
@@ -452,6 +121,10 @@ async fn async_main() -> Result<(), EspError> {
     let timer_service = esp_idf_svc::timer::EspTaskTimerService::new()?;
 
     let is_wokwi_simulator = check_is_wokwi()?;
+    let safe_mode = matches!(
+        ResetReason::get(),
+        ResetReason::Panic | ResetReason::TaskWatchdog | ResetReason::CPULockup
+    );
 
     let driver = LedcTimerDriver::new(
         peripherals.ledc.timer2,
@@ -498,6 +171,12 @@ async fn async_main() -> Result<(), EspError> {
             &driver,
             peripherals.pins.gpio26,
         )?),
+        // White
+        DebugLed::new(LedcDriver::new(
+            peripherals.ledc.channel5,
+            &driver,
+            peripherals.pins.gpio27,
+        )?),
     ];
 
     // Prepare to move the LED drivers into a short-lock critical section
@@ -505,9 +184,7 @@ async fn async_main() -> Result<(), EspError> {
     // ~100Hz will perform the dithered writes from a tight (interrupt-like)
     // context so other async work doesn't interfere with the timing.
 
-    // Keep the resolution for use later (power_levels is moved below).
     info!("Dimmer has resolution {}", power_levels[0].resolution());
-    let min_resolution_step = 0.1 / (power_levels[0].resolution() as f32);
 
     // blink_strips(&mut power_levels).await?;
 
@@ -515,44 +192,276 @@ async fn async_main() -> Result<(), EspError> {
     let desired: Arc<Vec<DebugLedDithered>> =
         Arc::new(power_levels.iter().map(DebugLed::to_dithered).collect());
 
-    // Small counter used to index the dither table.
-    let dither_index = Arc::new(AtomicUsize::new(0));
-    let last_dt = Arc::new(AtomicU32::new(0f32.to_bits()));
-
     // Schedule a periodic callback at ~100Hz (10ms). The EspTaskTimerService
     // callback executes in a timer/dispatch context; keep the body minimal.
     let timer = {
         let desired = desired.clone();
-        let dither_index = dither_index.clone();
         let mut last_t = Instant::now();
-        let last_dt = last_dt.clone();
         timer_service.timer(move || {
             let now_t = Instant::now();
             let dt_secs = (now_t - last_t).as_secs_f32();
             last_t = now_t;
-            last_dt.store((dt_secs * 1000.0).to_bits(), Ordering::Relaxed);
-            let idx = dither_index.fetch_add(1, Ordering::Relaxed);
             // Iterate quickly and perform dithered writes. Ignore errors.
             for (led, desired) in power_levels.iter_mut().zip(desired.iter()) {
-                let intensity = desired.update_smoothing(dt_secs);
-                let gamma = intensity * intensity; // simple gamma correction
-                let _ = led.set_duty_dithered(gamma, idx);
+                let duty = desired.update_smoothing(dt_secs);
+                let _ = led.set_duty_dithered(duty);
             }
         })?
     };
     timer.every(Duration::from_millis(2))?;
 
-    let (mac, blink_res) = tokio::join!(
-        start_wifi(
+    // The addressable strip takes about 30us per pixel to write, so it gets its own thread where
+    // the blocking writes don't delay the timers above
+    let strip_state = Arc::new(Mutex::new(StripState::default()));
+    match Ws2812Esp32RmtDriver::new(peripherals.rmt.channel0, peripherals.pins.gpio32) {
+        Ok(driver) => {
+            let strip_state = strip_state.clone();
+            let ws2812 = Ws2812Output::new(driver, StripConfig::default());
+            std::thread::Builder::new()
+                .name("strip".to_string())
+                .stack_size(8192)
+                .spawn(move || run_strip(ws2812, &strip_state))
+                .expect("failed to start the strip thread");
+        }
+        Err(e) => error!("Failed to start the addressable strip driver, it stays off: {e}"),
+    }
+
+    // The BOOT button on GPIO0 pulls the pin low when pressed. It is polled from a timer so
+    // gestures are timed accurately even while the main loop sleeps.
+    let mut button_pin = PinDriver::input(peripherals.pins.gpio0)?;
+    button_pin.set_pull(Pull::Up)?;
+    let (gesture_sender, gestures) = tokio::sync::mpsc::unbounded_channel();
+    let button_timer = {
+        let mut detector = GestureDetector::default();
+        let start = Instant::now();
+        timer_service.timer(move || {
+            if let Some(gesture) = detector.update(button_pin.is_low(), start.elapsed()) {
+                let _ = gesture_sender.send(gesture);
+            }
+        })?
+    };
+    button_timer.every(Duration::from_millis(10))?;
+
+    let (network_sender, network_messages) = tokio::sync::mpsc::channel(NETWORK_QUEUE_LENGTH);
+    // Other tasks can subscribe to follow the connection
+    let wifi_status = tokio::sync::watch::Sender::new(WifiStatus::default());
+    let state_nvs = EspNvs::new(nvs.clone(), STATE_NAMESPACE, true)?;
+    let last_known = match load_state(&state_nvs) {
+        Ok(Some(state)) => state,
+        Ok(None) => RemoteState::default(),
+        Err(e) => {
+            send_to_network(
+                &network_sender,
+                NetworkMessage::Status(format!("Failed to load the saved state: {e}")),
+            );
+            RemoteState::default()
+        }
+    };
+    let remote = Arc::new(Mutex::new(last_known));
+    let not_before = env!("BUILD_UNIX_TIME")
+        .parse()
+        .ok()
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .unwrap_or_default();
+    // The system clock survives soft resets, and so does the last sync
+    let time_source = Arc::new(Mutex::new(TimeSource::restore(not_before, &saved_clock())));
+
+    let device_config = if is_wokwi_simulator {
+        Ok(DeviceConfig::wokwi())
+    } else {
+        load_device_config(nvs.clone())
+    };
+
+    // The lights run from the last-known state right away, the network catches up in the background
+    let (network, lights) = tokio::join!(
+        run_network(
             peripherals.modem,
-            sys_loop.clone(),
+            sys_loop,
             nvs,
-            timer_service.clone(),
+            timer_service,
+            device_config,
             is_wokwi_simulator,
+            safe_mode,
+            debug_led,
+            remote.clone(),
+            time_source.clone(),
+            wifi_status,
+            network_messages,
+        ),
+        run_lights(
+            remote,
+            time_source,
+            network_sender,
+            desired,
+            strip_state,
+            adc_pin,
+            gestures,
+            state_nvs,
+            safe_mode,
         ),
-        blink_strips_d(&desired),
     );
-    blink_res?;
+    network.and(lights)
+}
+
+/// Reads the provisioned [`DeviceConfig`], see `make provision`.
+fn load_device_config(nvs: EspDefaultNvsPartition) -> Result<DeviceConfig, String> {
+    // Opening the namespace fails if nothing has been provisioned
+    let nvs = EspNvs::new(nvs, device_config::NAMESPACE, false).map_err(|e| {
+        format!(
+            "Failed to open the {} namespace: {e}",
+            device_config::NAMESPACE
+        )
+    })?;
+    let mut buf = [0; device_config::MAX_VALUE_LEN];
+    DeviceConfig::load(|key| match nvs.get_str(key, &mut buf) {
+        Ok(value) => value.map(str::to_string),
+        Err(e) => {
+            warn!("Failed to read {key}: {e}");
+            None
+        }
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Kept over soft resets, but garbage after a power cut, which [`SavedClock`] detects
+#[link_section = ".rtc_noinit"]
+static mut SAVED_CLOCK: MaybeUninit<SavedClock> = MaybeUninit::uninit();
+
+fn saved_clock() -> SavedClock {
+    // Safety: every bit pattern is a valid SavedClock
+    unsafe {
+        std::ptr::addr_of!(SAVED_CLOCK)
+            .read_volatile()
+            .assume_init()
+    }
+}
+
+fn save_clock(clock: SavedClock) {
+    // Safety: only written from the SNTP callback
+    unsafe { std::ptr::addr_of_mut!(SAVED_CLOCK).write_volatile(MaybeUninit::new(clock)) }
+}
+
+/// Time between the frames written to the addressable strip
+const STRIP_FRAME_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Renders the effect on the addressable strip at a fixed frame rate. While the strip is not the
+/// selected output nothing is written, except one frame to turn it off.
+fn run_strip<S: PixelStrip>(mut ws2812: Ws2812Output<S>, strip_state: &Mutex<StripState>) -> ! {
+    let mut renderer = EffectRenderer::new();
+    let mut frame = Vec::new();
+    let start = Instant::now();
+    // Whatever the strip showed before a soft reset is turned off first
+    let mut is_lit = true;
+    let mut next_frame = Instant::now();
+    loop {
+        next_frame = (next_frame + STRIP_FRAME_INTERVAL).max(Instant::now());
+        std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+
+        // Copy the state so the lock isn't held during the write
+        let Ok(state) = strip_state.lock().map(|s| s.clone()) else {
+            continue;
+        };
+        if state.color.is_none() && !is_lit {
+            continue;
+        }
+        is_lit = state.color.is_some();
+        if state.config != *ws2812.config() {
+            ws2812.set_config(state.config.clone());
+        }
+        frame.clear();
+        if let Some(color) = state.color {
+            frame.resize(state.config.pixels as usize, [0.0; 4]);
+            renderer.render(&state.effect, color, start.elapsed(), &mut frame);
+            for pixel in frame.iter_mut() {
+                *pixel = state
+                    .config
+                    .calibration
+                    .apply_rgbw(pixel.map(|c| c / 255.0))
+                    .map(|d| d * state.limit);
+            }
+        }
+        let _ = ws2812.write_frame(&frame);
+    }
+}
+
+/// NVS namespace of the persisted [`RemoteState`]
+const STATE_NAMESPACE: &str = "lights";
+const STATE_KEY: &str = "state";
+
+fn load_state(nvs: &EspNvs<NvsDefault>) -> Result<Option<RemoteState>, String> {
+    let Some(len) = nvs.blob_len(STATE_KEY).map_err(|e| e.to_string())? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0; len];
+    let Some(bytes) = nvs
+        .get_raw(STATE_KEY, &mut buf)
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+    persist::decode(bytes).map(Some).map_err(|e| e.to_string())
+}
+
+/// Messages from the light loop to MQTT.
+enum NetworkMessage {
+    Status(String),
+    Override(Option<ManualOverride>),
+    KnobPosition(u32),
+    LightsActual([u32; 4]),
+}
+
+/// Messages are queued while MQTT is not connected. Once the queue is full, new ones are dropped.
+const NETWORK_QUEUE_LENGTH: usize = 32;
+
+fn send_to_network(network: &Sender<NetworkMessage>, message: NetworkMessage) {
+    if network.try_send(message).is_err() {
+        log::debug!("Network queue is full, dropping a message");
+    }
+}
+
+/// Connects to Wi-Fi and MQTT, forwards the messages from the light loop and keeps the
+/// shared [`RemoteState`] up to date with the MQTT containers.
+#[allow(clippy::too_many_arguments)]
+async fn run_network(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    timer_service: EspTaskTimerService,
+    device_config: Result<DeviceConfig, String>,
+    is_wokwi_simulator: bool,
+    safe_mode: bool,
+    mut debug_led: DebugLed<LedcDriver<'_>>,
+    remote: Arc<Mutex<RemoteState>>,
+    time_source: Arc<Mutex<TimeSource>>,
+    wifi_status: tokio::sync::watch::Sender<WifiStatus>,
+    mut messages: Receiver<NetworkMessage>,
+) -> Result<(), EspError> {
+    let mut wifi_updates = wifi_status.subscribe();
+    let config = match device_config {
+        Ok(config) => {
+            info!("Device configuration: {config:?}");
+            Some(config)
+        }
+        Err(e) => {
+            error!("No usable device configuration, starting the setup portal: {e}");
+            None
+        }
+    };
+
+    let mac = start_wifi(
+        modem,
+        sys_loop,
+        nvs,
+        timer_service,
+        config.as_ref(),
+        is_wokwi_simulator,
+        wifi_status,
+    )
+    .await;
+    // Without a configuration start_wifi runs the setup portal and restarts
+    let Some(config) = config else {
+        unreachable!("connected to Wi-Fi without a configuration");
+    };
 
     // convert mac to string
     let mac_str = format!(
@@ -560,17 +469,34 @@ async fn async_main() -> Result<(), EspError> {
         mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
     );
 
-    let ntp = EspSntp::new_default().unwrap();
+    let monotonic_start = Instant::now();
+    let _sntp = EspSntp::new_with_callback(&SntpConf::default(), move |since_epoch| {
+        let Some(utc) = chrono::DateTime::from_timestamp(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos(),
+        ) else {
+            return;
+        };
+        if let Ok(mut time_source) = time_source.lock() {
+            time_source.synced(utc, monotonic_start.elapsed());
+            save_clock(time_source.save());
+            info!(
+                "Time synced, drift {:?} ppm",
+                time_source.drift_ppm().map(|d| d.round())
+            );
+        }
+    })
+    .unwrap();
 
     info!("Creating storage...");
 
-    let device_id = format!("{MQTT_CLIENT_ID} {mac_str}");
+    let device_id = format!("{} {mac_str}", config.mqtt_client_id);
     info!("Device ID: {}", device_id);
     let storage = SyncStorage::new(
         &device_id,
-        MQTT_HOST,
-        MQTT_USERNAME,
-        MQTT_PASSWORD,
+        &config.mqtt_host,
+        &config.mqtt_username,
+        &config.mqtt_password,
         brevduva::SessionPersistance::Persistent,
     )
     .await;
@@ -589,34 +515,26 @@ async fn async_main() -> Result<(), EspError> {
         .await
         .unwrap();
 
-    match ResetReason::get() {
-        ResetReason::Panic | ResetReason::TaskWatchdog | ResetReason::CPULockup => {
-            debug_led
-                .blink(3, Duration::from_millis(200))
-                .await
-                .unwrap();
-            status_channel
-                .send("Restart was due to panic. Entering safe mode for 30 seconds.".to_string())
-                .await;
-            // Sleep
-            debug_led
-                .blink(30, Duration::from_millis(500))
-                .await
-                .unwrap();
-            status_channel
-                .send("Exiting safe mode after panic.".to_string())
-                .await;
-        }
-        _ => { /* all good */ }
+    if safe_mode {
+        debug_led.blink(3, Duration::from_millis(200)).await?;
+        status_channel
+            .send("Restart was due to panic. Entering safe mode for 30 seconds.".to_string())
+            .await;
+        // Sleep
+        debug_led.blink(30, Duration::from_millis(500)).await?;
+        status_channel
+            .send("Exiting safe mode after panic.".to_string())
+            .await;
     }
 
+    // Containers start out with the last-known values, so they are kept if the broker has
+    // nothing retained
+    let last_known = remote.lock().map(|r| r.clone()).unwrap_or_default();
+
     let alarm_state = storage
         .add_container_with_mode(
             "alarm/state",
-            InnerAlarmState {
-                next_alarm: Default::default(),
-                enabled: false,
-            },
+            last_known.alarm_state.clone(),
             SerializationFormat::Auto,
             ReadWriteMode::ReadOnly,
         )
@@ -643,19 +561,54 @@ async fn async_main() -> Result<(), EspError> {
     let alarm_last_played = storage
         .add_container_with_mode(
             "alarm/last_played",
-            AlarmLastPlayed {
-                last_played_time: None,
-            },
+            last_known.alarm_last_played.clone(),
             SerializationFormat::Auto,
             ReadWriteMode::ReadOnly,
         )
         .await
         .unwrap();
 
-    let lights = storage
-        .add_container::<Option<[u32; 4]>>(
-            &format!("lights/{device_id}/rgba"),
-            None,
+    let override_container = storage
+        .add_container::<Option<ManualOverride>>(
+            &format!("lights/{device_id}/override"),
+            last_known.manual_override.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let knob_config = storage
+        .add_container::<KnobConfig>(
+            &format!("lights/{device_id}/knob/config"),
+            last_known.knob.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    // Knob position in percent
+    let knob_position = storage
+        .add_container::<u32>(
+            &format!("lights/{device_id}/knob"),
+            0,
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let button_config = storage
+        .add_container::<ButtonConfig>(
+            &format!("lights/{device_id}/button"),
+            last_known.button.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let alarm_priority = storage
+        .add_container::<u32>(
+            "lights/config/alarm_priority",
+            last_known.alarm_priority,
             SerializationFormat::Auto,
         )
         .await
@@ -676,264 +629,503 @@ async fn async_main() -> Result<(), EspError> {
     // const SNOOZE_LIGHT: [f32; 4] = [0.0, 0.0, 60.0, 0.0];
 
     let snooze_light_color = storage
-        .add_container::<RGBColor>(
+        .add_container::<SceneLight>(
             &format!("lights/colors/snooze"),
-            RGBColor { r: 0, g: 0, b: 60 },
+            last_known.colors.snooze,
             SerializationFormat::Auto,
         )
         .await
         .unwrap();
 
     let plant_light_color = storage
-        .add_container::<RGBColor>(
+        .add_container::<SceneLight>(
             &format!("lights/colors/plant"),
-            RGBColor {
-                r: 255,
-                g: 255,
-                b: 60,
-            },
+            last_known.colors.plant,
             SerializationFormat::Auto,
         )
         .await
         .unwrap();
 
     let evening_light_color = storage
-        .add_container::<RGBColor>(
+        .add_container::<SceneLight>(
             &format!("lights/colors/evening"),
-            RGBColor {
-                r: 20,
-                g: 128,
-                b: 160,
-            },
+            last_known.colors.evening,
             SerializationFormat::Auto,
         )
         .await
         .unwrap();
 
     let in_bed_light_color = storage
-        .add_container::<RGBColor>(
+        .add_container::<SceneLight>(
             &format!("lights/colors/in_bed"),
-            RGBColor { r: 0, g: 0, b: 0 },
+            last_known.colors.in_bed,
             SerializationFormat::Auto,
         )
         .await
         .unwrap();
 
-    info!("Light...");
+    let sunrise_curve = storage
+        .add_container::<Option<SunriseCurve>>(
+            "lights/animations/sunrise",
+            last_known.sunrise.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
 
-    let led_pin = peripherals.pins.gpio32; // TODO: 33
-    let channel = peripherals.rmt.channel0;
-    // let mut ws2812 = LedPixelEsp32Rmt::<RGB8, LedPixelColorGrb24>::new(channel, led_pin).unwrap();
+    let schedule = storage
+        .add_container::<Schedule>(
+            "lights/schedule",
+            last_known.schedule.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
 
-    info!("Waiting for sync...");
+    let calibration_container = storage
+        .add_container::<Option<OutputCalibration>>(
+            &format!("lights/{device_id}/calibration"),
+            last_known.calibration.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
 
-    debug_led.blink(1, Duration::from_millis(100)).await?;
+    let transition_config = storage
+        .add_container::<TransitionConfig>(
+            "lights/transition",
+            last_known.transition,
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
 
-    // Wait until we have current time from network
-    while ntp.get_sync_status() != esp_idf_svc::sntp::SyncStatus::Completed {
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    }
+    let timezone_config = storage
+        .add_container::<String>(
+            "lights/config/timezone",
+            last_known.timezone.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let brightness_ceiling = storage
+        .add_container::<u32>(
+            "lights/brightness/ceiling",
+            last_known.limits.ceiling,
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
 
-    debug_led.blink(2, Duration::from_millis(40)).await?;
+    let night_brightness_cap = storage
+        .add_container::<u32>(
+            "lights/brightness/night_cap",
+            last_known.limits.night_cap,
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let output_container = storage
+        .add_container::<OutputConfig>(
+            &format!("lights/{device_id}/output"),
+            last_known.output.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    let effect_container = storage
+        .add_container::<Effect>(
+            &format!("lights/{device_id}/effect"),
+            last_known.effect.clone(),
+            SerializationFormat::Auto,
+        )
+        .await
+        .unwrap();
+
+    info!("Waiting for sync...");
+
+    debug_led.blink(1, Duration::from_millis(100)).await?;
 
     storage.wait_for_sync().await;
 
     debug_led.blink(10, Duration::from_millis(20)).await?;
 
-    status_channel.send(format!("Starting...")).await;
+    status_channel.send(format!("Started")).await;
 
-    info!("Loop...");
+    loop {
+        if wifi_updates.has_changed().unwrap_or(false) {
+            let wifi = wifi_updates.borrow_and_update().clone();
+            // Lit while the connection is down
+            if let Err(e) = debug_led.set_duty(if wifi.state.is_connected() { 0.0 } else { 0.05 }) {
+                warn!("Failed to set the debug LED: {e}");
+            }
+            // MQTT is down with the connection, so only the reconnects get reported
+            if wifi.state.is_connected() {
+                status_channel.send(wifi.to_string()).await;
+            }
+        }
 
-    let mut last = Instant::now();
-    let mut wakeup_start = None;
-    let mut last_played_time = None;
-    let mut last_played_trigger_time = None;
+        while let Ok(message) = messages.try_recv() {
+            match message {
+                NetworkMessage::Status(status) => status_channel.send(status).await,
+                NetworkMessage::Override(value) => override_container.set(value).await,
+                NetworkMessage::KnobPosition(percent) => knob_position.set(percent).await,
+                NetworkMessage::LightsActual(levels) => lights_actual.set(Some(levels)).await,
+            }
+        }
 
-    let mut current_color: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
-    let fade_speed = 0.2;
+        // Nothing can run between the queue being emptied and this, so a change the light
+        // loop made to the shared state is never replaced by an outdated container value
+        let latest = RemoteState {
+            alarm_state: alarm_state.get().unwrap(),
+            alarm_last_played: alarm_last_played.get().unwrap(),
+            is_playing: is_playing.get().unwrap(),
+            is_user_in_bed: is_user_in_bed.get().unwrap(),
+            colors: SceneColors {
+                plant: plant_light_color.get().unwrap(),
+                evening: evening_light_color.get().unwrap(),
+                in_bed: in_bed_light_color.get().unwrap(),
+                snooze: snooze_light_color.get().unwrap(),
+            },
+            schedule: schedule.get().unwrap(),
+            sunrise: sunrise_curve.get().unwrap(),
+            manual_override: override_container.get().unwrap(),
+            alarm_priority: alarm_priority.get().unwrap(),
+            calibration: calibration_container.get().unwrap(),
+            transition: transition_config.get().unwrap(),
+            timezone: timezone_config.get().unwrap(),
+            limits: OutputLimits {
+                ceiling: brightness_ceiling.get().unwrap(),
+                night_cap: night_brightness_cap.get().unwrap(),
+            },
+            output: output_container.get().unwrap(),
+            effect: effect_container.get().unwrap(),
+            knob: knob_config.get().unwrap(),
+            button: button_config.get().unwrap(),
+        };
+        if let Ok(mut remote) = remote.lock() {
+            *remote = latest;
+        }
 
-    let mut last_color = [0.0, 0.0, 0.0, 0.0];
-    let mut target_color: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-    // TODO: Figure out how to set the local timezone
-    let tz = FixedOffset::east_opt(3600 * 1).unwrap();
+/// Sets the manual override locally, and on MQTT once it is connected.
+fn set_override(
+    state: &mut RemoteState,
+    remote: &Mutex<RemoteState>,
+    network: &Sender<NetworkMessage>,
+    value: Option<ManualOverride>,
+) {
+    state.manual_override = value.clone();
+    if let Ok(mut remote) = remote.lock() {
+        remote.manual_override = value.clone();
+    }
+    send_to_network(network, NetworkMessage::Override(value));
+}
 
-    status_channel.send(format!("Started")).await;
+/// Decides on the light output from the shared [`RemoteState`] and the local inputs.
+#[allow(clippy::too_many_arguments)]
+async fn run_lights(
+    remote: Arc<Mutex<RemoteState>>,
+    time_source: Arc<Mutex<TimeSource>>,
+    network: Sender<NetworkMessage>,
+    desired: Arc<Vec<DebugLedDithered>>,
+    strip_state: Arc<Mutex<StripState>>,
+    mut adc_pin: AdcChannelDriver<'_, Gpio34, &AdcDriver<'_, ADC1>>,
+    mut gestures: UnboundedReceiver<Gesture>,
+    mut state_nvs: EspNvs<NvsDefault>,
+    safe_mode: bool,
+) -> Result<(), EspError> {
+    let status = |message: String| send_to_network(&network, NetworkMessage::Status(message));
+
+    if safe_mode {
+        // Give the network time to receive an update in case the light loop is what panicked
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+
+    info!("Loop...");
+
+    let start = Instant::now();
+    let mut write_limiter = WriteLimiter::default();
+    if let Ok(state) = remote.lock() {
+        write_limiter.written(&persist::encode(&state), start.elapsed());
+    }
+
+    let mut scene_engine = SceneEngine::new();
+
+    let mut crossfade = Crossfade::new([0.0, 0.0, 0.0, 0.0], Instant::now());
+    let mut last_reason = None;
+
+    let mut target_color: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
+    // What the LEDs show, after the limits and the knob
+    let mut shown_color = [0.0; 4];
+    let mut last_sunrise_curve = None;
+    let mut calibration = OutputCalibration::default();
+    let mut last_calibration_config = None;
+    let mut output = OutputConfig::default();
+    let mut last_output_config = None;
+    let mut effect = Effect::default();
+    let mut last_effect_config = None;
+    let mut knob = KnobFilter::default();
+    let mut scene_cycle = 0;
+
+    let mut tz = Timezone::from_config(DEFAULT_TIMEZONE).unwrap();
+    let mut last_timezone_config = None;
+    let mut last_time_quality = None;
 
     for it in 0.. {
         let t = Instant::now();
-        let dt = t - last;
-        last = t;
 
-        {
-            let now = Utc::now().with_timezone(&tz);
-            let is_evening = now.hour() >= 17;
-            let is_morning = now.hour() < 11;
-
-            target_color = if is_evening {
-                evening_light_color.get().unwrap().into()
-            } else {
-                plant_light_color.get().unwrap().into()
+        let Ok(mut state) = remote.lock().map(|remote| remote.clone()) else {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        };
+
+        let timezone = state.timezone.clone();
+        if Some(&timezone) != last_timezone_config.as_ref() {
+            match Timezone::from_config(&timezone) {
+                Ok(v) => tz = v,
+                Err(e) => status(format!("{e}")),
+            }
+            last_timezone_config = Some(timezone);
+        }
+
+        let calibration_config = state.calibration.clone();
+        if calibration_config != last_calibration_config {
+            calibration = match calibration_config.as_ref().map(|c| (c, c.validate())) {
+                Some((c, Ok(()))) => c.clone(),
+                Some((_, Err(e))) => {
+                    status(format!("Invalid calibration, using the default: {e}"));
+                    OutputCalibration::default()
+                }
+                None => OutputCalibration::default(),
             };
+            last_calibration_config = calibration_config;
+        }
+
+        let output_config = state.output.clone();
+        if Some(&output_config) != last_output_config.as_ref() {
+            output = match output_config.validate() {
+                Ok(()) => output_config.clone(),
+                Err(e) => {
+                    status(format!("Invalid output config, using the default: {e}"));
+                    OutputConfig::default()
+                }
+            };
+            last_output_config = Some(output_config);
+        }
 
-            let alarm_state_mutex = alarm_state.get();
-            let alarm_state_v = alarm_state_mutex.as_ref().unwrap();
-            let time_until_next_alarm = alarm_state_v.next_alarm.signed_duration_since(&now);
-
-            let alarm_set_very_soon =
-                alarm_state_v.enabled && time_until_next_alarm.num_seconds() < 30; // Start wakeup light a little while before alarm
-            let alarm_has_passed = time_until_next_alarm.num_seconds() < -60; // Allow for some leeway in clock sync between devices
-            if is_playing.get().unwrap() || (alarm_set_very_soon && !alarm_has_passed) {
-                if wakeup_start.is_none() {
-                    wakeup_start = Some(Instant::now());
-                    last_played_time = Some(Instant::now());
-                    last_played_trigger_time = Some(alarm_state_v.next_alarm.clone());
-                    status_channel
-                        .send("Detected alarm is playing".to_string())
-                        .await;
+        let effect_config = state.effect.clone();
+        if Some(&effect_config) != last_effect_config.as_ref() {
+            effect = match effect_config.validate() {
+                Ok(()) => effect_config.clone(),
+                Err(e) => {
+                    status(format!("Invalid effect, using a solid color: {e}"));
+                    Effect::default()
                 }
-                target_color = get_wakup_color(wakeup_start.unwrap().elapsed().as_secs_f32());
-            } else {
-                if wakeup_start.is_some() {
-                    wakeup_start = None;
-                    status_channel
-                        .send("Detected alarm stopped playing".to_string())
-                        .await;
+            };
+            last_effect_config = Some(effect_config);
+        }
+
+        let sunrise = state.sunrise.clone();
+        if sunrise != last_sunrise_curve {
+            let curve = match sunrise.as_ref().map(|c| (c, c.validate())) {
+                Some((c, Ok(()))) => c.clone(),
+                Some((_, Err(e))) => {
+                    status(format!(
+                        "Invalid sunrise curve, using the built-in one: {e}"
+                    ));
+                    SunriseCurve::builtin()
                 }
+                None => SunriseCurve::builtin(),
+            };
+            scene_engine.set_sunrise(curve);
+            last_sunrise_curve = sunrise;
+        }
 
-                let alarm_set_soon = alarm_state_v.enabled
-                    && time_until_next_alarm.num_hours() < 12
-                    && time_until_next_alarm.num_seconds() >= 0;
-
-                let alarm_last_played_time = alarm_last_played.get().clone().unwrap();
-                let alarm_played_recently = alarm_last_played_time
-                    .last_played_time
-                    .map(|v| now.signed_duration_since(v).num_minutes() < 30)
-                    .unwrap_or(false);
-
-                let is_night = now.hour() < 11 || now.hour() > 22;
-
-                if alarm_played_recently
-                    && alarm_state_v.enabled
-                    && last_played_trigger_time == Some(alarm_state_v.next_alarm)
-                {
-                    // When the alarm was played recently and is still enabled with the same trigger time,
-                    // assume the user has snoozed.
-                    target_color = snooze_light_color.get().unwrap().into();
-                } else if is_night || alarm_set_soon || alarm_played_recently {
-                    // Disable light:
-                    // - during nighttime
-                    // - when the user is in bed
-                    // - if an alarm is set to some time within the next few hours (likely that the user is in bed)
-                    // - if the alarm was finished relatively recently (make sure the user has enough time to get out of bed).
-                    target_color = in_bed_light_color.get().unwrap().into();
-                } else if is_user_in_bed.get().unwrap() {
-                    if is_evening || is_morning {
-                        target_color = in_bed_light_color.get().unwrap().into();
+        let knob_config = state.knob.clone();
+        // A failed reading only skips this sample, the lights keep running
+        let knob_moved = match adc_pin.read_raw() {
+            Ok(raw) => knob.update(raw as f32 / ADC_MAX),
+            Err(e) => {
+                warn!("Failed to read the knob: {e}");
+                None
+            }
+        };
+        let knob_percent = (knob.position() * 100.0).round() as u32;
+        if knob_moved.is_some() || it == 0 {
+            send_to_network(&network, NetworkMessage::KnobPosition(knob_percent));
+        }
+        if knob_moved.is_some() && knob_config.mode == KnobMode::Override {
+            let knob_override = Some(ManualOverride {
+                color: SceneLight {
+                    color: knob_config.override_color,
+                    brightness: knob_percent,
+                },
+                source: "knob".to_string(),
+                priority: 0,
+                expires: Some(OverrideExpiry::SceneChange),
+            });
+            set_override(&mut state, &remote, &network, knob_override);
+        }
+
+        while let Ok(gesture) = gestures.try_recv() {
+            let button_config = state.button.clone();
+            let Some(action) = button_config.action(gesture) else {
+                continue;
+            };
+            let button_override = |color: SceneLight| {
+                Some(ManualOverride {
+                    color,
+                    source: "button".to_string(),
+                    priority: 0,
+                    expires: Some(OverrideExpiry::SceneChange),
+                })
+            };
+            match action {
+                ButtonAction::ToggleLight => {
+                    let color = if shown_color.iter().any(|&c| c > 0.0) {
+                        SceneLight {
+                            color: RGBWColor::default(),
+                            brightness: 0,
+                        }
                     } else {
-                        // If the user is in bed during daytime, set a soft light to
-                        // avoid complete darkness.
-                        target_color = evening_light_color.get().unwrap().into();
+                        button_config.on_color.into()
+                    };
+                    set_override(&mut state, &remote, &network, button_override(color));
+                }
+                ButtonAction::CycleScenes => {
+                    let colors = [
+                        state.colors.plant,
+                        state.colors.evening,
+                        state.colors.in_bed,
+                    ];
+                    let color = colors[scene_cycle % colors.len()];
+                    scene_cycle += 1;
+                    set_override(&mut state, &remote, &network, button_override(color));
+                }
+                ButtonAction::SnoozeSunrise => {
+                    if scene_engine.is_waking_up() {
+                        let minutes = button_config.snooze_minutes;
+                        let now = Utc::now();
+                        scene_engine.snooze(now, now + chrono::Duration::minutes(minutes as i64));
+                        status(format!("Wakeup light snoozed for {minutes} minutes"));
+                    }
+                }
+                ButtonAction::DismissSunrise => {
+                    if scene_engine.is_waking_up() {
+                        scene_engine.dismiss(&state.alarm_state);
+                        status("Wakeup light dismissed".to_string());
                     }
                 }
             }
         }
 
-        // current_color = lerp(current_color, target_color, dt.as_secs_f32() * fade_speed);
-
-        let mut gamma = [
-            (target_color[0] / 255.0),
-            (target_color[1] / 255.0),
-            (target_color[2] / 255.0),
-            (target_color[3] / 255.0),
-        ];
-
-        if let Some(color) = lights.get().unwrap() {
-            gamma = [
-                color[0] as f32 / 100.0,
-                color[1] as f32 / 100.0,
-                color[2] as f32 / 100.0,
-                color[3] as f32 / 100.0,
-            ];
+        let now = Utc::now();
+        let time_quality = time_source
+            .lock()
+            .map(|t| t.quality(now))
+            .unwrap_or(TimeQuality::Unknown);
+        if last_time_quality.map(|q| std::mem::discriminant(&q))
+            != Some(std::mem::discriminant(&time_quality))
+        {
+            status(format!("Time quality: {time_quality:?}"));
+        }
+        last_time_quality = Some(time_quality);
+        let inputs = state.scene_inputs(tz.to_local(now), time_quality);
+        let scene = scene_engine.update(&inputs);
+        let is_night = time_quality.is_known()
+            && inputs.schedule.scene_at(inputs.now.naive_local()) == Scene::Night;
+        let mut limit = state.limits.scale(is_night);
+        if knob_config.mode == KnobMode::Brightness {
+            limit *= knob.position();
         }
+        match scene.event {
+            Some(SceneEvent::AlarmStarted) => {
+                status("Detected alarm is playing".to_string());
+            }
+            Some(SceneEvent::AlarmStopped) => {
+                status("Detected alarm stopped playing".to_string());
+            }
+            None => {}
+        }
+        if let Some(manual) = &inputs.manual_override {
+            match scene.override_event {
+                Some(OverrideEvent::Preempted) => {
+                    status(format!(
+                        "Wakeup light took over from the override by '{}'",
+                        manual.source
+                    ));
+                }
+                Some(OverrideEvent::Expired) => {
+                    status(format!("Override by '{}' expired", manual.source));
+                    set_override(&mut state, &remote, &network, None);
+                }
+                None => {}
+            }
+        }
+        // The sunrise changes a little every time, any other new color is faded to, like a new
+        // override or scene color from MQTT
+        let color_changed = scene.color != target_color && scene.reason != SceneReason::Wakeup;
+        target_color = scene.color;
+
+        if last_reason != Some(scene.reason) || color_changed {
+            crossfade.start(target_color, state.transition, t);
+            last_reason = Some(scene.reason);
+        } else {
+            crossfade.retarget(target_color);
+        }
+        let current_color = crossfade.color(t);
+        shown_color = current_color.map(|c| c * limit);
+
+        let gamma = [
+            (current_color[0] / 255.0),
+            (current_color[1] / 255.0),
+            (current_color[2] / 255.0),
+            (current_color[3] / 255.0),
+        ];
 
         // if it % 100 == 0 {
         //     let status = format!("{:?} {:?} {:?}", current_color, target_color, gamma);
         //     println!("{}", status);
         // }
 
-        // gamma[0] = (adc_pin.read_raw()? as f32 / 4095.0).powi(3);
-
-        // if gamma
-        //     .iter()
-        //     .zip(&last_color)
-        //     .all(|(&g, &l)| (g - l).abs() < min_resolution_step)
-        //     && it != 0
-        // {
-        //     // If there's no significant color change, go to low power mode
-        //     // after a while to save power.
-        //     // Wait for a few iterations to run dithering a bit before stabilizing,
-        //     // and to avoid accidentally entering low power during smooth transitions.
-        //     low_power_counter += 1;
-        //     if low_power_counter > 50 {
-        //         if !low_power {
-        //             status_channel.send(format!("Entering low power")).await;
-        //             low_power = true;
-        //         }
-
-        //         tokio::time::sleep(Duration::from_millis(10)).await;
-        //         continue;
-        //     }
-        // } else {
-        //     low_power = false;
-        //     low_power_counter = 0;
-        // }
-        // last_color = gamma;
-
-        desired[0].set_intensity(gamma[0])?;
-        desired[1].set_intensity(gamma[1])?;
-        desired[2].set_intensity(gamma[2])?;
-
-        // let pixels = std::iter::repeat(gamma)
-        //     .enumerate()
-        //     .map(|(i, col)| {
-        //         let r = DITHER[i % DITHER.len()] & 0x3;
-        //         const SCALING: u32 = 2048 / 256;
-        //         // RGBW8::new_alpha(
-        //         //     ((col[0] + r) / SCALING) as u8,
-        //         //     ((col[1] + r) / SCALING) as u8,
-        //         //     ((col[2] + r) / SCALING) as u8,
-        //         //     White(((col[3] + r) / SCALING) as u8),
-        //         // )
-        //         RGB8::new(
-        //             ((col[0] + r) / SCALING).min(255) as u8,
-        //             ((col[1] + r) / SCALING).min(255) as u8,
-        //             ((col[2] + r) / SCALING).min(255) as u8,
-        //         )
-        //     })
-        //     .take(60);
-
-        // // let t_write = Instant::now();
-        // ws2812.write(pixels).unwrap();
-        // println!("{:?} {:?}", dt, t_write.elapsed());
-        // tokio::task::yield_now().await;
+        let duty = if output.backend.uses_pwm() {
+            calibration.apply_rgbw(gamma).map(|d| d * limit)
+        } else {
+            [0.0; 4]
+        };
+        for (led, duty) in desired.iter().zip(duty) {
+            led.set_intensity(duty);
+        }
+
+        if let Ok(mut state) = strip_state.lock() {
+            *state = StripState {
+                config: output.strip.clone(),
+                effect: effect.clone(),
+                color: output.backend.uses_ws2812().then_some(current_color),
+                limit,
+            };
+        }
 
         if it % 20 == 0 {
-            lights_actual
-                .set(Some([
-                    (gamma[0] * 100.0) as u32,
-                    (gamma[1] * 100.0) as u32,
-                    (gamma[2] * 100.0) as u32,
-                    (gamma[3] * 100.0) as u32,
-                ]))
-                .await;
-
-            // let last_dt = f32::from_bits(last_dt.load(Ordering::Relaxed));
-            // status_channel
-            //     .send(format!("Target: {gamma:.3?} {dt:.3?} {last_dt:.3?}"))
-            //     .await;
-            // status_channel.send(format!("{last_dt:.3?}")).await;
-            // info!("{last_dt:.3?}");
+            let levels = gamma.map(|g| (g * 100.0) as u32);
+            send_to_network(&network, NetworkMessage::LightsActual(levels));
+        }
+
+        if it % 10 == 0 {
+            let encoded = persist::encode(&state);
+            if write_limiter.should_write(&encoded, start.elapsed()) {
+                match state_nvs.set_raw(STATE_KEY, &encoded) {
+                    Ok(_) => write_limiter.written(&encoded, start.elapsed()),
+                    Err(e) => warn!("Failed to save the state: {e}"),
+                }
+            }
         }
 
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+
+use crate::color::SceneLight;
+
+/// When a manual override stops applying.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideExpiry {
+    At(DateTime<Utc>),
+    /// When the scene that was active when the override was set changes
+    SceneChange,
+}
+
+/// A manual color that replaces the scene color for a while.
+///
+/// ```json
+/// {"color": "2700K at 60%", "source": "wall switch", "priority": 10, "expires": "scene_change"}
+/// ```
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct ManualOverride {
+    pub color: SceneLight,
+    /// Who set the override, only used for status messages
+    #[serde(default)]
+    pub source: String,
+    /// The wakeup light only replaces overrides with a lower priority than the alarm priority
+    #[serde(default)]
+    pub priority: u32,
+    /// The override lasts until it is cleared if this is not set
+    #[serde(default)]
+    pub expires: Option<OverrideExpiry>,
+}
+
+/// Priority of the wakeup light when there is no configured one. Overrides with the default
+/// priority are replaced by the wakeup light.
+pub const DEFAULT_ALARM_PRIORITY: u32 = 50;
@@ -0,0 +1,251 @@
+//! Keeping the last-known [`RemoteState`] over reboots, so the lights behave the same when the
+//! broker can't be reached after a power cut.
+//!
+//! The state is stored as JSON with a version number. A state written by an older firmware is
+//! migrated when it is loaded, and one from a newer firmware is ignored.
+use std::time::Duration;
+
+use crate::button::ButtonConfig;
+use crate::calibration::{OutputCalibration, OutputLimits};
+use crate::effects::Effect;
+use crate::knob::KnobConfig;
+use crate::manual::ManualOverride;
+use crate::remote::RemoteState;
+use crate::scene::{AlarmLastPlayed, InnerAlarmState, SceneColors};
+use crate::schedule::Schedule;
+use crate::strip::OutputConfig;
+use crate::sunrise::SunriseCurve;
+use crate::transition::TransitionConfig;
+
+/// Version of the format written by [`encode`]
+pub const PERSIST_VERSION: u32 = 1;
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum PersistError {
+    #[error("the saved state has version {0}, which is newer than this firmware supports")]
+    UnsupportedVersion(u32),
+    #[error("the saved state is invalid: {0}")]
+    Invalid(String),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Envelope {
+    version: u32,
+    state: serde_json::Value,
+}
+
+/// Everything in [`RemoteState`] except whether the alarm is playing and whether the user is in
+/// bed, which can't be trusted after a reboot.
+///
+/// Fields that are missing get their default value, so new fields can be added without a new
+/// version.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+struct PersistedV1 {
+    alarm_state: InnerAlarmState,
+    alarm_last_played: AlarmLastPlayed,
+    colors: SceneColors,
+    schedule: Schedule,
+    sunrise: Option<SunriseCurve>,
+    manual_override: Option<ManualOverride>,
+    alarm_priority: u32,
+    calibration: Option<OutputCalibration>,
+    transition: TransitionConfig,
+    timezone: String,
+    limits: OutputLimits,
+    output: OutputConfig,
+    effect: Effect,
+    knob: KnobConfig,
+    button: ButtonConfig,
+}
+
+impl Default for PersistedV1 {
+    fn default() -> Self {
+        Self::from(&RemoteState::default())
+    }
+}
+
+impl From<&RemoteState> for PersistedV1 {
+    fn from(state: &RemoteState) -> Self {
+        Self {
+            alarm_state: state.alarm_state.clone(),
+            alarm_last_played: state.alarm_last_played.clone(),
+            colors: state.colors,
+            schedule: state.schedule.clone(),
+            sunrise: state.sunrise.clone(),
+            manual_override: state.manual_override.clone(),
+            alarm_priority: state.alarm_priority,
+            calibration: state.calibration.clone(),
+            transition: state.transition,
+            timezone: state.timezone.clone(),
+            limits: state.limits,
+            output: state.output.clone(),
+            effect: state.effect.clone(),
+            knob: state.knob.clone(),
+            button: state.button.clone(),
+        }
+    }
+}
+
+impl From<PersistedV1> for RemoteState {
+    fn from(persisted: PersistedV1) -> Self {
+        Self {
+            alarm_state: persisted.alarm_state,
+            alarm_last_played: persisted.alarm_last_played,
+            colors: persisted.colors,
+            schedule: persisted.schedule,
+            sunrise: persisted.sunrise,
+            manual_override: persisted.manual_override,
+            alarm_priority: persisted.alarm_priority,
+            calibration: persisted.calibration,
+            transition: persisted.transition,
+            timezone: persisted.timezone,
+            limits: persisted.limits,
+            output: persisted.output,
+            effect: persisted.effect,
+            knob: persisted.knob,
+            button: persisted.button,
+            ..RemoteState::default()
+        }
+    }
+}
+
+pub fn encode(state: &RemoteState) -> Vec<u8> {
+    let envelope = Envelope {
+        version: PERSIST_VERSION,
+        state: serde_json::to_value(PersistedV1::from(state)).unwrap(),
+    };
+    serde_json::to_vec(&envelope).unwrap()
+}
+
+pub fn decode(bytes: &[u8]) -> Result<RemoteState, PersistError> {
+    let invalid = |e: serde_json::Error| PersistError::Invalid(e.to_string());
+    let envelope: Envelope = serde_json::from_slice(bytes).map_err(invalid)?;
+    match envelope.version {
+        1 => Ok(serde_json::from_value::<PersistedV1>(envelope.state)
+            .map_err(invalid)?
+            .into()),
+        version => Err(PersistError::UnsupportedVersion(version)),
+    }
+}
+
+/// Decides when to write the state to flash.
+///
+/// Flash sectors only survive a limited number of erases, so a changed state is only written
+/// once it has stopped changing for a while, e.g. when the knob is no longer being turned, and
+/// never more often than a minimum interval.
+#[derive(Debug, Clone)]
+pub struct WriteLimiter {
+    settle: Duration,
+    min_interval: Duration,
+    written: Option<Vec<u8>>,
+    last_write: Option<Duration>,
+    pending: Option<(Vec<u8>, Duration)>,
+}
+
+impl Default for WriteLimiter {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5), Duration::from_secs(60))
+    }
+}
+
+impl WriteLimiter {
+    pub fn new(settle: Duration, min_interval: Duration) -> Self {
+        Self {
+            settle,
+            min_interval,
+            written: None,
+            last_write: None,
+            pending: None,
+        }
+    }
+
+    /// Feeds the current encoded state at time `now`, measured from any fixed point. Returns true
+    /// if it should be written now.
+    pub fn should_write(&mut self, encoded: &[u8], now: Duration) -> bool {
+        if self.written.as_deref() == Some(encoded) {
+            self.pending = None;
+            return false;
+        }
+        let since = match &self.pending {
+            Some((pending, since)) if pending == encoded => *since,
+            _ => {
+                self.pending = Some((encoded.to_vec(), now));
+                now
+            }
+        };
+        let interval_passed = match self.last_write {
+            Some(last) => now.saturating_sub(last) >= self.min_interval,
+            None => true,
+        };
+        now.saturating_sub(since) >= self.settle && interval_passed
+    }
+
+    /// Records that `encoded` is what is stored, either because it was written or loaded.
+    pub fn written(&mut self, encoded: &[u8], now: Duration) {
+        self.written = Some(encoded.to_vec());
+        self.last_write = Some(now);
+        self.pending = None;
+    }
+}
+
+#[test]
+fn test_persist_round_trip() {
+    let mut state = RemoteState::default();
+    state.colors.evening = "2200K at 30%"
+        .parse::<crate::color::RGBWColor>()
+        .unwrap()
+        .into();
+    state.alarm_state.enabled = true;
+    state.manual_override = Some(serde_json::from_str(r#"{"color": "rgb(255,0,0)"}"#).unwrap());
+    state.limits.night_cap = 20;
+    // Not persisted
+    state.is_playing = true;
+
+    let decoded = decode(&encode(&state)).unwrap();
+    assert_eq!(
+        decoded,
+        RemoteState {
+            is_playing: false,
+            ..state
+        }
+    );
+
+    // A state from before a field existed gets the default for it
+    let old = br#"{"version": 1, "state": {"alarm_priority": 70}}"#;
+    let decoded = decode(old).unwrap();
+    assert_eq!(decoded.alarm_priority, 70);
+    assert_eq!(decoded.colors, SceneColors::default());
+
+    assert_eq!(
+        decode(br#"{"version": 2, "state": {}}"#),
+        Err(PersistError::UnsupportedVersion(2))
+    );
+    assert!(matches!(decode(b"\xff\xff"), Err(PersistError::Invalid(_))));
+}
+
+#[test]
+fn test_write_limiter() {
+    let s = Duration::from_secs;
+    let mut limiter = WriteLimiter::default();
+    limiter.written(b"a", s(0));
+    assert!(!limiter.should_write(b"a", s(100)));
+
+    // Written once it has settled
+    assert!(!limiter.should_write(b"b", s(100)));
+    assert!(!limiter.should_write(b"b", s(104)));
+    assert!(limiter.should_write(b"b", s(105)));
+    limiter.written(b"b", s(105));
+
+    // A value that keeps changing is not written
+    for t in 106..120 {
+        assert!(!limiter.should_write(&[t as u8], s(t)));
+    }
+    // And it waits for the minimum interval since the last write
+    assert!(!limiter.should_write(b"c", s(120)));
+    assert!(!limiter.should_write(b"c", s(164)));
+    assert!(limiter.should_write(b"c", s(165)));
+
+    // Changing back to what is stored needs no write
+    assert!(!limiter.should_write(b"b", s(166)));
+}
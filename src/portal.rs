@@ -0,0 +1,308 @@
+//! The parts of the setup portal that don't need the hardware: the HTML form, parsing what it
+//! posts, and answering DNS queries so phones open the portal when they join the access point.
+use crate::device_config::{
+    DeviceConfig, DeviceConfigError, KEY_DNS, KEY_GATEWAY, KEY_HOSTNAME, KEY_MQTT_CLIENT_ID,
+    KEY_MQTT_HOST, KEY_MQTT_PASSWORD, KEY_MQTT_USERNAME, KEY_PORTAL_PASSWORD, KEY_STATIC_IP,
+    WIFI_NETWORK_KEYS,
+};
+
+/// Name of the open access point the portal is served on
+pub const PORTAL_SSID: &str = "bedroom-lights-setup";
+
+/// Decodes an `application/x-www-form-urlencoded` body.
+pub fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(key), url_decode(value))
+        })
+        .collect()
+}
+
+fn url_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next(), input.next()];
+                let decoded = match hex {
+                    [Some(h), Some(l)] => std::str::from_utf8(&[h, l])
+                        .ok()
+                        .and_then(|h| u8::from_str_radix(h, 16).ok()),
+                    _ => None,
+                };
+                // Keep malformed escapes as they are
+                match decoded {
+                    Some(d) => bytes.push(d),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Builds the device configuration from the posted form. The form fields are named like the
+/// NVS keys, and empty fields count as missing. See [`DeviceConfig::validate_new`].
+pub fn config_from_form(body: &str) -> Result<DeviceConfig, DeviceConfigError> {
+    let form = parse_form(body);
+    let config = DeviceConfig::load(|key| {
+        form.iter()
+            .find(|(k, v)| k == key && !v.is_empty())
+            .map(|(_, v)| v.trim().to_string())
+    })?;
+    config.validate_new()?;
+    Ok(config)
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The setup page, pre-filled with `values` (NVS key and value). Passwords are never filled in.
+pub fn render_form(values: &[(&str, &str)], error: Option<&str>) -> String {
+    let mut fields = Vec::new();
+    for (i, (ssid_key, password_key)) in WIFI_NETWORK_KEYS.into_iter().enumerate() {
+        let (ssid_label, password_label) = match i {
+            0 => ("Wi-Fi network".to_string(), "Wi-Fi password".to_string()),
+            _ => (
+                format!("Wi-Fi network {} (optional)", i + 1),
+                format!("Wi-Fi password {}", i + 1),
+            ),
+        };
+        fields.push((ssid_key, ssid_label, "text"));
+        fields.push((password_key, password_label, "password"));
+    }
+    fields.extend([
+        (KEY_HOSTNAME, "Hostname (optional)".to_string(), "text"),
+        (
+            KEY_STATIC_IP,
+            "Static IP address, like 192.168.1.50/24 (empty for DHCP)".to_string(),
+            "text",
+        ),
+        (
+            KEY_GATEWAY,
+            "Gateway (with a static IP)".to_string(),
+            "text",
+        ),
+        (
+            KEY_DNS,
+            "DNS servers, comma separated (with a static IP, optional)".to_string(),
+            "text",
+        ),
+        (
+            KEY_MQTT_HOST,
+            "MQTT broker (mqtt://host:port)".to_string(),
+            "text",
+        ),
+        (KEY_MQTT_CLIENT_ID, "MQTT client id".to_string(), "text"),
+        (KEY_MQTT_USERNAME, "MQTT username".to_string(), "text"),
+        (KEY_MQTT_PASSWORD, "MQTT password".to_string(), "password"),
+        (
+            KEY_PORTAL_PASSWORD,
+            "Setup portal password (optional, the Wi-Fi password is used otherwise)".to_string(),
+            "password",
+        ),
+    ]);
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>Bedroom lights setup</title></head><body><h1>Bedroom lights setup</h1>",
+    );
+    if let Some(error) = error {
+        html += &format!("<p style=\"color:red\">{}</p>", html_escape(error));
+    }
+    html += "<form method=\"post\" action=\"/\">";
+    for (key, label, input_type) in fields {
+        let value = match input_type {
+            "password" => "",
+            _ => values
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map_or("", |(_, v)| *v),
+        };
+        html += &format!(
+            "<p><label>{label}<br><input type=\"{input_type}\" name=\"{key}\" value=\"{}\"></label></p>",
+            html_escape(value)
+        );
+    }
+    html += "<p><button type=\"submit\">Save and restart</button></p></form></body></html>";
+    html
+}
+
+/// The page shown once the configuration has been saved
+pub fn render_saved(config: &DeviceConfig) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Bedroom lights setup</title>\
+         </head><body><p>Saved. The lights restart and connect to {}.</p></body></html>",
+        html_escape(
+            &config
+                .wifi_networks
+                .iter()
+                .map(|n| n.ssid.as_str())
+                .collect::<Vec<_>>()
+                .join(" or ")
+        )
+    )
+}
+
+/// Answers a DNS query for an A record of any name with `ip`. Queries for other record types
+/// get an empty answer. Returns `None` for anything that isn't a well-formed query.
+pub fn dns_response(query: &[u8], ip: [u8; 4]) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let is_response = query[2] & 0x80 != 0;
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+    if is_response || question_count == 0 {
+        return None;
+    }
+
+    // Find the end of the first question: the name as length prefixed labels, then the type and class
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xc0 != 0 {
+            // Compressed names don't appear in the question of a query
+            return None;
+        }
+        pos += 1 + len;
+    }
+    let question = query.get(HEADER_LEN..pos + 4)?;
+    let record_type =
+        u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    const TYPE_A: u16 = 1;
+    const TYPE_ANY: u16 = 255;
+    let answer = matches!(record_type, TYPE_A | TYPE_ANY);
+
+    let mut response = Vec::with_capacity(pos + 20);
+    response.extend_from_slice(&query[0..2]);
+    // A response, authoritative, with the recursion desired flag of the query and no error
+    response.push(0x84 | (query[2] & 0x01));
+    response.push(0x00);
+    response.extend_from_slice(&[0, 1, 0, answer as u8, 0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    if answer {
+        // Pointer to the name in the question
+        response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        // Class IN
+        response.extend_from_slice(&[0, 1]);
+        // Time to live, short so the real addresses are used once the device leaves the portal
+        response.extend_from_slice(&60u32.to_be_bytes());
+        response.extend_from_slice(&[0, 4]);
+        response.extend_from_slice(&ip);
+    }
+    Some(response)
+}
+
+#[test]
+fn test_portal_form() {
+    assert_eq!(
+        parse_form("a=1&b=hello+world%21&c=&d&e=%zz%4"),
+        [
+            ("a", "1"),
+            ("b", "hello world!"),
+            ("c", ""),
+            ("d", ""),
+            ("e", "%zz%4")
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+    );
+
+    let config = config_from_form(
+        "wifi_ssid=My+network&wifi_pass=p%C3%A4ssword&mqtt_host=mqtt%3A%2F%2Fbroker%3A1883&mqtt_client_id=",
+    )
+    .unwrap();
+    assert_eq!(config.wifi_networks.len(), 1);
+    assert_eq!(config.wifi_networks[0].ssid, "My network");
+    assert_eq!(config.wifi_networks[0].password, "pässword");
+    assert_eq!(config.mqtt_host, "mqtt://broker:1883");
+    // Left empty in the form
+    assert_eq!(config.mqtt_client_id, "bedroom_lights");
+
+    assert_eq!(
+        config_from_form("wifi_ssid=&mqtt_host=mqtt%3A%2F%2Fbroker"),
+        Err(DeviceConfigError::Missing(WIFI_NETWORK_KEYS[0].0))
+    );
+
+    let entries = config.entries();
+    let values: Vec<_> = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let html = render_form(&values, Some("<bad>"));
+    assert!(html.contains("value=\"My network\""));
+    assert!(!html.contains("pässword"));
+    assert!(html.contains("&lt;bad&gt;"));
+    assert!(render_saved(&config).contains("My network"));
+
+    // Only the extra networks that are filled in are used
+    let config = config_from_form(
+        "wifi_ssid=Home&wifi_pass=password&wifi_ssid_2=&wifi_ssid_3=Cabin&mqtt_host=mqtt%3A%2F%2Fbroker",
+    )
+    .unwrap();
+    assert_eq!(config.wifi_networks.len(), 2);
+    let entries = config.entries();
+    let values: Vec<_> = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    assert!(render_form(&values, None).contains("value=\"Cabin\""));
+
+    // The station interface settings
+    let config = config_from_form(
+        "wifi_ssid=Home&portal_pass=password&mqtt_host=mqtt%3A%2F%2Fbroker&hostname=lamp&static_ip=10.0.0.9%2F8&gateway=10.0.0.1&dns=",
+    )
+    .unwrap();
+    assert_eq!(config.hostname.as_deref(), Some("lamp"));
+    assert_eq!(config.static_ip.unwrap().dns, None);
+    // An open network needs a password for the setup portal
+    assert_eq!(
+        config_from_form("wifi_ssid=Home&mqtt_host=mqtt%3A%2F%2Fbroker"),
+        Err(DeviceConfigError::PortalPasswordRequired)
+    );
+}
+
+#[test]
+fn test_dns_response() {
+    // Query for an A record of example.com with recursion desired
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00");
+    query.extend_from_slice(&[0, 1, 0, 1]);
+
+    let response = dns_response(&query, [192, 168, 71, 1]).unwrap();
+    assert_eq!(&response[0..2], &[0x12, 0x34]);
+    assert_eq!(response[2] & 0x80, 0x80);
+    // One answer
+    assert_eq!(&response[6..8], &[0, 1]);
+    assert_eq!(&response[12..query.len()], &query[12..]);
+    assert_eq!(&response[response.len() - 4..], &[192, 168, 71, 1]);
+
+    // AAAA queries get no answer, so the phone falls back to IPv4
+    let last = query.len() - 3;
+    query[last] = 28;
+    let response = dns_response(&query, [192, 168, 71, 1]).unwrap();
+    assert_eq!(&response[6..8], &[0, 0]);
+    assert_eq!(response.len(), query.len());
+
+    assert_eq!(dns_response(&query[..20], [192, 168, 71, 1]), None);
+    assert_eq!(dns_response(&[0; 4], [192, 168, 71, 1]), None);
+}
@@ -0,0 +1,237 @@
+//! Setup portal: an access point with a form for the [`DeviceConfig`], used when none is
+//! provisioned or the configured network can't be reached.
+//!
+//! Once the device is configured, the access point is protected with
+//! [`DeviceConfig::portal_ap_password`], so only the owner can see and change the settings.
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bedroom_lights3::device_config::{self, DeviceConfig};
+use bedroom_lights3::portal::{
+    config_from_form, dns_response, parse_form, render_form, render_saved, PORTAL_SSID,
+};
+use esp_idf_svc::{
+    http::{
+        server::{Configuration as HttpConfiguration, EspHttpServer},
+        Method,
+    },
+    io::{EspIOError, Read, Write},
+    nvs::{EspDefaultNvsPartition, EspNvs},
+    sys::EspError,
+    wifi::{AccessPointConfiguration, AsyncWifi, AuthMethod, Configuration, EspWifi},
+};
+use log::{error, info, warn};
+
+/// Longest form body that is read, well above what the fields can hold
+const MAX_FORM_LEN: usize = 2048;
+
+/// Serves the setup portal until a valid configuration has been saved, or until `timeout` has
+/// passed. Returns whether a configuration was saved. The access point is stopped on a timeout.
+pub async fn run_portal(
+    wifi: &mut AsyncWifi<EspWifi<'_>>,
+    nvs: EspDefaultNvsPartition,
+    current: Option<&DeviceConfig>,
+    timeout: Option<Duration>,
+) -> Result<bool, EspError> {
+    if wifi.is_started()? {
+        wifi.stop().await?;
+    }
+    // A device that isn't configured yet has nothing to protect, and its owner no password
+    let password = current.map_or("", DeviceConfig::portal_ap_password);
+    if current.is_some() && password.is_empty() {
+        warn!("The setup portal is open, set a portal password to protect the configuration");
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: PORTAL_SSID.try_into().unwrap(),
+        auth_method: if password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        // The length is checked by DeviceConfig::validate
+        password: password.try_into().unwrap(),
+        channel: 1,
+        max_connections: 4,
+        ..Default::default()
+    }))?;
+    wifi.start().await?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!(
+        "Setup portal running on the {PORTAL_SSID} network{} at http://{ip}/",
+        if password.is_empty() {
+            ""
+        } else {
+            " with the setup password"
+        }
+    );
+
+    // The server isn't Send, so it lives on its own thread together with the DNS server
+    let saved = Arc::new(AtomicBool::new(false));
+    let stop = Arc::new(AtomicBool::new(false));
+    let server = {
+        let saved = saved.clone();
+        let stop = stop.clone();
+        let current = current.cloned();
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || {
+                if let Err(e) = serve(nvs, current, ip, saved, &stop) {
+                    error!("Setup portal failed: {e}");
+                }
+            })
+            .expect("failed to start the setup portal thread")
+    };
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    while !saved.load(Ordering::Relaxed) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("Stopping the setup portal");
+            stop.store(true, Ordering::Relaxed);
+            while !server.is_finished() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            wifi.stop().await?;
+            return Ok(false);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    // Give the browser time to receive the response
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    Ok(true)
+}
+
+/// Runs the web and DNS servers until `stop` is set.
+fn serve(
+    nvs: EspDefaultNvsPartition,
+    current: Option<DeviceConfig>,
+    ip: Ipv4Addr,
+    saved: Arc<AtomicBool>,
+    stop: &AtomicBool,
+) -> Result<(), EspIOError> {
+    let current = Arc::new(Mutex::new(current));
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Post, {
+        let current = current.clone();
+        move |mut req| -> Result<(), EspIOError> {
+            let mut body = Vec::new();
+            let mut buf = [0; 256];
+            loop {
+                let n = req.read(&mut buf)?;
+                if n == 0 || body.len() + n > MAX_FORM_LEN {
+                    break;
+                }
+                body.extend_from_slice(&buf[..n]);
+            }
+            let body = String::from_utf8_lossy(&body);
+
+            let result = config_from_form(&body)
+                .map_err(|e| e.to_string())
+                .and_then(|config| match save(nvs.clone(), &config) {
+                    Ok(()) => Ok(config),
+                    Err(e) => Err(format!("Failed to save the settings: {e}")),
+                });
+            let html = match result {
+                Ok(config) => {
+                    info!("Saved the device configuration from the setup portal: {config:?}");
+                    let html = render_saved(&config);
+                    *current.lock().unwrap() = Some(config);
+                    saved.store(true, Ordering::Relaxed);
+                    html
+                }
+                Err(e) => {
+                    warn!("Invalid settings from the setup portal: {e}");
+                    let form = parse_form(&body);
+                    let values: Vec<_> =
+                        form.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                    render_form(&values, Some(&e))
+                }
+            };
+            req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+                .write_all(html.as_bytes())
+        }
+    })?;
+
+    // Any other page shows the form, which is what makes phones pop up the portal
+    server.fn_handler("/*", Method::Get, move |req| -> Result<(), EspIOError> {
+        let entries = current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(DeviceConfig::entries)
+            .unwrap_or_default();
+        let values: Vec<_> = entries.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let html = render_form(&values, None);
+        req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?
+            .write_all(html.as_bytes())
+    })?;
+
+    if let Err(e) = run_dns(ip, stop) {
+        error!("Failed to start the DNS server of the setup portal: {e}");
+        // The form can still be opened by its address, so keep the server running
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+    Ok(())
+}
+
+/// Answers every name with the portal until `stop` is set
+fn run_dns(ip: Ipv4Addr, stop: &AtomicBool) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    // Wakes up now and then to check whether to stop
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let mut buf = [0; 512];
+    while !stop.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                if let Some(response) = dns_response(&buf[..len], ip.octets()) {
+                    if let Err(e) = socket.send_to(&response, from) {
+                        warn!("Failed to answer a DNS query: {e}");
+                    }
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => {
+                warn!("DNS server failed: {e}");
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn save(nvs: EspDefaultNvsPartition, config: &DeviceConfig) -> Result<(), EspError> {
+    let mut nvs = EspNvs::new(nvs, device_config::NAMESPACE, true)?;
+    let entries = config.entries();
+    for (key, value) in &entries {
+        nvs.set_str(key, value)?;
+    }
+    // Networks that are no longer used
+    for (ssid_key, password_key) in device_config::WIFI_NETWORK_KEYS
+        .into_iter()
+        .skip(config.wifi_networks.len())
+    {
+        nvs.remove(ssid_key)?;
+        nvs.remove(password_key)?;
+    }
+    // Settings that were cleared
+    for key in device_config::IP_KEYS
+        .into_iter()
+        .chain([device_config::KEY_PORTAL_PASSWORD])
+    {
+        if !entries.iter().any(|(k, _)| *k == key) {
+            nvs.remove(key)?;
+        }
+    }
+    Ok(())
+}
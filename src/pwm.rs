@@ -0,0 +1,49 @@
+/// A single PWM output.
+pub trait PwmChannel {
+    type Error;
+
+    fn set_duty_raw(&mut self, duty: u32) -> Result<(), Self::Error>;
+    fn max_duty(&self) -> u32;
+}
+
+#[cfg(feature = "esp")]
+impl PwmChannel for esp_idf_svc::hal::ledc::LedcDriver<'_> {
+    type Error = esp_idf_svc::sys::EspError;
+
+    fn set_duty_raw(&mut self, duty: u32) -> Result<(), Self::Error> {
+        self.set_duty(duty)
+    }
+
+    fn max_duty(&self) -> u32 {
+        self.get_max_duty()
+    }
+}
+
+/// Records every duty written to it, for testing the light pipeline on the host.
+#[derive(Debug, Clone)]
+pub struct RecordingPwm {
+    pub max_duty: u32,
+    pub duties: Vec<u32>,
+}
+
+impl RecordingPwm {
+    pub fn new(max_duty: u32) -> Self {
+        Self {
+            max_duty,
+            duties: Vec::new(),
+        }
+    }
+}
+
+impl PwmChannel for RecordingPwm {
+    type Error = std::convert::Infallible;
+
+    fn set_duty_raw(&mut self, duty: u32) -> Result<(), Self::Error> {
+        self.duties.push(duty.min(self.max_duty));
+        Ok(())
+    }
+
+    fn max_duty(&self) -> u32 {
+        self.max_duty
+    }
+}
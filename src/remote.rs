@@ -0,0 +1,89 @@
+use chrono::{DateTime, FixedOffset};
+
+use crate::button::ButtonConfig;
+use crate::calibration::{OutputCalibration, OutputLimits};
+use crate::clock::TimeQuality;
+use crate::effects::Effect;
+use crate::knob::KnobConfig;
+use crate::manual::{ManualOverride, DEFAULT_ALARM_PRIORITY};
+use crate::scene::{AlarmLastPlayed, InnerAlarmState, SceneColors, SceneInputs};
+use crate::schedule::Schedule;
+use crate::strip::OutputConfig;
+use crate::sunrise::SunriseCurve;
+use crate::timezone::DEFAULT_TIMEZONE;
+use crate::transition::TransitionConfig;
+
+/// Last-known values of everything the lights get over MQTT.
+///
+/// The light loop only works from this, so it keeps running with the last values while the
+/// network is down, and with the defaults before it has ever connected.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RemoteState {
+    pub alarm_state: InnerAlarmState,
+    pub alarm_last_played: AlarmLastPlayed,
+    pub is_playing: bool,
+    pub is_user_in_bed: bool,
+    pub colors: SceneColors,
+    pub schedule: Schedule,
+    pub sunrise: Option<SunriseCurve>,
+    pub manual_override: Option<ManualOverride>,
+    pub alarm_priority: u32,
+    pub calibration: Option<OutputCalibration>,
+    pub transition: TransitionConfig,
+    pub timezone: String,
+    pub limits: OutputLimits,
+    pub output: OutputConfig,
+    pub effect: Effect,
+    pub knob: KnobConfig,
+    pub button: ButtonConfig,
+}
+
+impl Default for RemoteState {
+    fn default() -> Self {
+        Self {
+            alarm_state: InnerAlarmState {
+                next_alarm: Default::default(),
+                enabled: false,
+            },
+            alarm_last_played: AlarmLastPlayed {
+                last_played_time: None,
+            },
+            is_playing: false,
+            is_user_in_bed: false,
+            colors: SceneColors::default(),
+            schedule: Schedule::default(),
+            sunrise: None,
+            manual_override: None,
+            alarm_priority: DEFAULT_ALARM_PRIORITY,
+            calibration: None,
+            transition: TransitionConfig::default(),
+            timezone: DEFAULT_TIMEZONE.to_string(),
+            limits: OutputLimits::default(),
+            output: OutputConfig::default(),
+            effect: Effect::default(),
+            knob: KnobConfig::default(),
+            button: ButtonConfig::default(),
+        }
+    }
+}
+
+impl RemoteState {
+    pub fn scene_inputs(
+        &self,
+        now: DateTime<FixedOffset>,
+        time_quality: TimeQuality,
+    ) -> SceneInputs {
+        SceneInputs {
+            now,
+            time_quality,
+            alarm_state: self.alarm_state.clone(),
+            alarm_last_played: self.alarm_last_played.clone(),
+            is_playing: self.is_playing,
+            is_user_in_bed: self.is_user_in_bed,
+            colors: self.colors,
+            schedule: self.schedule.clone(),
+            manual_override: self.manual_override.clone(),
+            alarm_priority: self.alarm_priority,
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Choosing which of the known Wi-Fi networks, and which of their access points, to connect to.
+use std::time::Duration;
+
+use crate::device_config::WifiNetwork;
+
+/// An access point found in a scan.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct SeenAccessPoint {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    /// In dBm
+    pub rssi: i8,
+}
+
+/// What to connect to next.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Candidate {
+    /// Index into the known networks
+    pub network: usize,
+    /// The access point to use, `None` to let the driver pick, e.g. for a network that was not
+    /// found in the scan because its SSID is hidden
+    pub bssid: Option<[u8; 6]>,
+    pub channel: Option<u8>,
+    pub rssi: Option<i8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RoamingConfig {
+    /// Only look for a better access point while the signal is weaker than this, in dBm
+    pub weak_rssi: i8,
+    /// How much stronger another access point must be to switch to it, in dB
+    pub min_improvement: i8,
+    /// Time between the checks of the signal strength
+    pub check_interval: Duration,
+}
+
+impl Default for RoamingConfig {
+    fn default() -> Self {
+        Self {
+            weak_rssi: -75,
+            min_improvement: 8,
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Failed attempts in a row after which a network is tried after the others, even if it is the
+/// strongest
+pub const FAILURES_BEFORE_FALLBACK: u32 = 3;
+
+/// Picks the network to connect to from a scan, preferring the strongest known one, and moves
+/// on to the other networks when one keeps failing.
+#[derive(Debug, Clone)]
+pub struct NetworkSelector {
+    networks: Vec<WifiNetwork>,
+    /// Failed attempts in a row per network
+    failures: Vec<u32>,
+}
+
+impl NetworkSelector {
+    pub fn new(networks: Vec<WifiNetwork>) -> Self {
+        let failures = vec![0; networks.len()];
+        Self { networks, failures }
+    }
+
+    pub fn networks(&self) -> &[WifiNetwork] {
+        &self.networks
+    }
+
+    /// Networks that keep failing drop behind the others, by one step for every
+    /// [`FAILURES_BEFORE_FALLBACK`] failures in a row, so they get tried again in turn.
+    fn tier(&self, network: usize) -> u32 {
+        self.failures[network] / FAILURES_BEFORE_FALLBACK
+    }
+
+    /// The strongest access point of each known network in the scan
+    fn strongest_seen<'a>(
+        &'a self,
+        scan: &'a [SeenAccessPoint],
+    ) -> impl Iterator<Item = (usize, &'a SeenAccessPoint)> + 'a {
+        self.networks.iter().enumerate().filter_map(|(i, network)| {
+            scan.iter()
+                .filter(|ap| ap.ssid == network.ssid)
+                .max_by_key(|ap| ap.rssi)
+                .map(|ap| (i, ap))
+        })
+    }
+
+    /// The network to try next. Networks seen in the scan come first, strongest first, then the
+    /// ones that were not seen in the order they are configured.
+    pub fn pick(&self, scan: &[SeenAccessPoint]) -> Option<Candidate> {
+        let seen: Vec<_> = self.strongest_seen(scan).collect();
+        let best_seen = seen
+            .iter()
+            .min_by_key(|(i, ap)| (self.tier(*i), -(ap.rssi as i32)))
+            .map(|(i, ap)| Candidate {
+                network: *i,
+                bssid: Some(ap.bssid),
+                channel: Some(ap.channel),
+                rssi: Some(ap.rssi),
+            });
+        let best_unseen = (0..self.networks.len())
+            .filter(|i| !seen.iter().any(|(s, _)| s == i))
+            .min_by_key(|i| (self.tier(*i), *i))
+            .map(|i| Candidate {
+                network: i,
+                bssid: None,
+                channel: None,
+                rssi: None,
+            });
+        match (best_seen, best_unseen) {
+            (Some(seen), Some(unseen)) if self.tier(unseen.network) < self.tier(seen.network) => {
+                Some(unseen)
+            }
+            (Some(seen), _) => Some(seen),
+            (None, unseen) => unseen,
+        }
+    }
+
+    pub fn connected(&mut self, network: usize) {
+        self.failures[network] = 0;
+    }
+
+    pub fn failed(&mut self, network: usize) {
+        self.failures[network] += 1;
+    }
+
+    /// A better access point than the current one, which has the signal strength `rssi`, if
+    /// the signal is weak and a known access point is clearly stronger.
+    pub fn roam(
+        &self,
+        config: &RoamingConfig,
+        current_bssid: [u8; 6],
+        rssi: i8,
+        scan: &[SeenAccessPoint],
+    ) -> Option<Candidate> {
+        if rssi >= config.weak_rssi {
+            return None;
+        }
+        self.strongest_seen(scan)
+            .filter(|(_, ap)| ap.bssid != current_bssid)
+            .filter(|(_, ap)| ap.rssi as i32 >= rssi as i32 + config.min_improvement as i32)
+            .max_by_key(|(_, ap)| ap.rssi)
+            .map(|(i, ap)| Candidate {
+                network: i,
+                bssid: Some(ap.bssid),
+                channel: Some(ap.channel),
+                rssi: Some(ap.rssi),
+            })
+    }
+}
+
+/// Formats a BSSID like `aa:bb:cc:dd:ee:ff`
+pub fn format_bssid(bssid: &[u8; 6]) -> String {
+    bssid
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[test]
+fn test_network_selector() {
+    let network = |ssid: &str| WifiNetwork {
+        ssid: ssid.to_string(),
+        password: String::new(),
+    };
+    let ap = |ssid: &str, id: u8, rssi: i8| SeenAccessPoint {
+        ssid: ssid.to_string(),
+        bssid: [0, 0, 0, 0, 0, id],
+        channel: id,
+        rssi,
+    };
+    let mut selector = NetworkSelector::new(vec![network("Home"), network("Upstairs")]);
+    let scan = [
+        ap("Neighbour", 1, -40),
+        ap("Home", 2, -70),
+        ap("Upstairs", 3, -60),
+        ap("Home", 4, -55),
+    ];
+
+    // The strongest access point of any known network
+    let picked = selector.pick(&scan).unwrap();
+    assert_eq!(
+        (picked.network, picked.bssid),
+        (0, Some([0, 0, 0, 0, 0, 4]))
+    );
+
+    // Falls back to the next network when it keeps failing, and back again later
+    for _ in 0..FAILURES_BEFORE_FALLBACK {
+        selector.failed(0);
+    }
+    assert_eq!(selector.pick(&scan).unwrap().network, 1);
+    for _ in 0..FAILURES_BEFORE_FALLBACK {
+        selector.failed(1);
+    }
+    assert_eq!(selector.pick(&scan).unwrap().network, 0);
+    selector.connected(0);
+    selector.connected(1);
+
+    // Networks missing from the scan are tried in order without a BSSID
+    let picked = selector.pick(&[ap("Neighbour", 1, -40)]).unwrap();
+    assert_eq!((picked.network, picked.bssid), (0, None));
+    let picked = selector.pick(&[ap("Upstairs", 3, -80)]).unwrap();
+    assert_eq!(picked.network, 1);
+    for _ in 0..FAILURES_BEFORE_FALLBACK {
+        selector.failed(1);
+    }
+    let picked = selector.pick(&[ap("Upstairs", 3, -80)]).unwrap();
+    assert_eq!((picked.network, picked.bssid), (0, None));
+    assert_eq!(NetworkSelector::new(vec![]).pick(&scan), None);
+
+    // Roams only when the signal is weak and another access point is clearly stronger
+    let config = RoamingConfig::default();
+    let current = [0, 0, 0, 0, 0, 2];
+    assert_eq!(selector.roam(&config, current, -70, &scan), None);
+    assert_eq!(
+        selector
+            .roam(&config, current, -80, &scan)
+            .map(|c| (c.network, c.bssid)),
+        Some((0, Some([0, 0, 0, 0, 0, 4])))
+    );
+    assert_eq!(
+        selector.roam(
+            &config,
+            current,
+            -80,
+            &[ap("Home", 2, -80), ap("Home", 5, -75)]
+        ),
+        None
+    );
+
+    assert_eq!(format_bssid(&[0xaa, 0, 1, 2, 3, 0xff]), "aa:00:01:02:03:ff");
+}
@@ -0,0 +1,652 @@
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::clock::TimeQuality;
+use crate::color::{RGBWColor, SceneLight};
+use crate::manual::{ManualOverride, OverrideExpiry};
+use crate::schedule::{Scene, Schedule};
+use crate::sunrise::SunriseCurve;
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct InnerAlarmState {
+    pub next_alarm: DateTime<Utc>,
+    pub enabled: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct AlarmLastPlayed {
+    pub last_played_time: Option<DateTime<Utc>>,
+}
+
+/// The user configurable colors of the different scenes.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct SceneColors {
+    pub plant: SceneLight,
+    pub evening: SceneLight,
+    pub in_bed: SceneLight,
+    pub snooze: SceneLight,
+}
+
+impl Default for SceneColors {
+    fn default() -> Self {
+        let rgb = |r, g, b| RGBWColor { r, g, b, w: 0 }.into();
+        Self {
+            plant: rgb(255, 255, 60),
+            evening: rgb(20, 128, 160),
+            in_bed: rgb(0, 0, 0),
+            snooze: rgb(0, 0, 60),
+        }
+    }
+}
+
+/// Everything the scene engine needs to know about the world to decide on a color.
+#[derive(Debug, Clone)]
+pub struct SceneInputs {
+    /// Current local time
+    pub now: DateTime<FixedOffset>,
+    /// How much `now` can be trusted. While it is unknown, the schedule and alarm times are
+    /// ignored, since acting on them could for example turn the lights on in the middle of the night.
+    pub time_quality: TimeQuality,
+    pub alarm_state: InnerAlarmState,
+    pub alarm_last_played: AlarmLastPlayed,
+    pub is_playing: bool,
+    pub is_user_in_bed: bool,
+    pub colors: SceneColors,
+    pub schedule: Schedule,
+    pub manual_override: Option<ManualOverride>,
+    /// Manual overrides with a lower priority than this are replaced by the wakeup light
+    pub alarm_priority: u32,
+}
+
+/// The wakeup light is only started from the alarm time if the clock is off by at most this
+const MAX_ALARM_TIME_ERROR: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Why the scene engine picked a particular color.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SceneReason {
+    Day,
+    Evening,
+    Wakeup,
+    Snooze,
+    Night,
+    AlarmSetSoon,
+    AlarmPlayedRecently,
+    InBed,
+    InBedDaytime,
+    Override,
+    /// The time is not known yet, so the schedule and alarms are not used
+    TimeUnknown,
+}
+
+/// Changes in the alarm state that are worth reporting on the status channel.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SceneEvent {
+    AlarmStarted,
+    AlarmStopped,
+}
+
+/// Changes of the manual override.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OverrideEvent {
+    /// The wakeup light took over from the override
+    Preempted,
+    /// The override expired and should be cleared
+    Expired,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SceneOutput {
+    /// Target color, in the range 0..255 per channel
+    pub color: [f32; 4],
+    pub reason: SceneReason,
+    pub event: Option<SceneEvent>,
+    pub override_event: Option<OverrideEvent>,
+}
+
+/// Decides what color the lights should have.
+///
+/// The engine does not do any IO, it only keeps track of the state needed to detect
+/// when the alarm starts and stops playing.
+#[derive(Default, Debug, Clone)]
+pub struct SceneEngine {
+    wakeup_start: Option<DateTime<Utc>>,
+    last_played_trigger_time: Option<DateTime<Utc>>,
+    active_override: Option<ActiveOverride>,
+    /// Wakeup light paused locally, e.g. with the button
+    snoozed_until: Option<DateTime<Utc>>,
+    /// When the wakeup light was paused, to continue the sunrise where it was
+    snoozed_at: Option<DateTime<Utc>>,
+    /// Trigger time of an alarm whose wakeup light was dismissed locally
+    dismissed_alarm: Option<DateTime<Utc>>,
+    /// Wakeup light dismissed locally while the phone plays the alarm, which is all that is
+    /// known of the alarm when the time is unknown
+    dismissed_playing: bool,
+    sunrise: SunriseCurve,
+}
+
+#[derive(Debug, Clone)]
+struct ActiveOverride {
+    value: ManualOverride,
+    /// What the lights would have shown without the override, for [`OverrideExpiry::SceneChange`]
+    scene: SceneReason,
+    /// Whether the time was known when `scene` was recorded
+    time_known: bool,
+    preempted: bool,
+    expired: bool,
+}
+
+impl SceneEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the animation of the wakeup light. The curve must be valid, see
+    /// [`SunriseCurve::validate`].
+    pub fn set_sunrise(&mut self, curve: SunriseCurve) {
+        self.sunrise = curve;
+    }
+
+    /// Pauses the wakeup light from `now` until `until`. It continues where it was afterwards.
+    pub fn snooze(&mut self, now: DateTime<Utc>, until: DateTime<Utc>) {
+        self.snoozed_until = Some(until);
+        self.snoozed_at.get_or_insert(now);
+    }
+
+    /// Turns off the wakeup light until the alarm is set to a different time, or while the time
+    /// is unknown, until the phone stops playing the alarm.
+    pub fn dismiss(&mut self, alarm_state: &InnerAlarmState) {
+        self.dismissed_alarm = Some(alarm_state.next_alarm);
+        self.dismissed_playing = true;
+        self.snoozed_until = None;
+        self.snoozed_at = None;
+    }
+
+    pub fn is_waking_up(&self) -> bool {
+        self.wakeup_start.is_some()
+    }
+
+    pub fn update(&mut self, inputs: &SceneInputs) -> SceneOutput {
+        let mut output = self.evaluate(inputs);
+
+        let Some(manual) = &inputs.manual_override else {
+            self.active_override = None;
+            return output;
+        };
+        let active = match &mut self.active_override {
+            Some(active) if active.value == *manual => active,
+            _ => self.active_override.insert(ActiveOverride {
+                value: manual.clone(),
+                scene: output.reason,
+                time_known: inputs.time_quality.is_known(),
+                preempted: false,
+                expired: false,
+            }),
+        };
+        if active.expired {
+            return output;
+        }
+        // The time becoming known or unknown is not a scene change, the scene before only
+        // couldn't be told. So the override, e.g. one restored at boot, stays.
+        if inputs.time_quality.is_known() != active.time_known {
+            active.scene = output.reason;
+            active.time_known = inputs.time_quality.is_known();
+        }
+
+        let preempted =
+            output.reason == SceneReason::Wakeup && manual.priority < inputs.alarm_priority;
+        if preempted && !active.preempted {
+            output.override_event = Some(OverrideEvent::Preempted);
+        }
+        active.preempted = preempted;
+
+        let expired = match manual.expires {
+            Some(OverrideExpiry::At(time)) => inputs.now >= time,
+            // A wakeup that does not preempt the override is not a scene change
+            Some(OverrideExpiry::SceneChange) => {
+                output.reason != active.scene && (preempted || output.reason != SceneReason::Wakeup)
+            }
+            None => false,
+        };
+        if expired {
+            active.expired = true;
+            output.override_event = Some(OverrideEvent::Expired);
+        } else if !preempted {
+            output.color = manual.color.into();
+            output.reason = SceneReason::Override;
+        }
+
+        output
+    }
+
+    fn evaluate(&mut self, inputs: &SceneInputs) -> SceneOutput {
+        let now = inputs.now;
+        let colors = &inputs.colors;
+        let alarm_state = &inputs.alarm_state;
+        if !inputs.is_playing {
+            self.dismissed_playing = false;
+        }
+        if !inputs.time_quality.is_known() {
+            return self.evaluate_without_time(inputs);
+        }
+        let scheduled = inputs.schedule.scene_at(now.naive_local());
+        let is_evening = scheduled == Scene::Evening;
+        let is_night = scheduled == Scene::Night;
+
+        let mut output = if is_evening {
+            SceneOutput {
+                color: colors.evening.into(),
+                reason: SceneReason::Evening,
+                event: None,
+                override_event: None,
+            }
+        } else {
+            SceneOutput {
+                color: colors.plant.into(),
+                reason: SceneReason::Day,
+                event: None,
+                override_event: None,
+            }
+        };
+
+        let time_until_next_alarm = alarm_state.next_alarm.signed_duration_since(now);
+
+        // Starting the wakeup light before the phone plays the alarm needs a clock that is about right
+        let alarm_time_usable = inputs
+            .time_quality
+            .max_error()
+            .is_some_and(|e| e <= MAX_ALARM_TIME_ERROR);
+        let alarm_set_very_soon =
+            alarm_time_usable && alarm_state.enabled && time_until_next_alarm.num_seconds() < 30; // Start wakeup light a little while before alarm
+        let alarm_has_passed = time_until_next_alarm.num_seconds() < -60; // Allow for some leeway in clock sync between devices
+        let dismissed = self.dismissed_alarm == Some(alarm_state.next_alarm);
+        if (inputs.is_playing || (alarm_set_very_soon && !alarm_has_passed)) && !dismissed {
+            let now_utc = now.with_timezone(&Utc);
+            if self.wakeup_start.is_none() {
+                self.wakeup_start = Some(now_utc);
+                self.last_played_trigger_time = Some(alarm_state.next_alarm);
+                output.event = Some(SceneEvent::AlarmStarted);
+            }
+            self.wakeup(now_utc, colors, &mut output);
+            return output;
+        }
+
+        if self.wakeup_start.is_some() {
+            self.wakeup_start = None;
+            self.snoozed_until = None;
+            self.snoozed_at = None;
+            output.event = Some(SceneEvent::AlarmStopped);
+        }
+
+        let alarm_set_soon = alarm_state.enabled
+            && time_until_next_alarm.num_hours() < 12
+            && time_until_next_alarm.num_seconds() >= 0;
+
+        let alarm_played_recently = inputs
+            .alarm_last_played
+            .last_played_time
+            .map(|v| now.signed_duration_since(v).num_minutes() < 30)
+            .unwrap_or(false);
+
+        if alarm_played_recently
+            && alarm_state.enabled
+            && self.last_played_trigger_time == Some(alarm_state.next_alarm)
+        {
+            // When the alarm was played recently and is still enabled with the same trigger time,
+            // assume the user has snoozed.
+            output.color = colors.snooze.into();
+            output.reason = SceneReason::Snooze;
+        } else if is_night || alarm_set_soon || alarm_played_recently {
+            // Disable light:
+            // - during nighttime
+            // - when the user is in bed
+            // - if an alarm is set to some time within the next few hours (likely that the user is in bed)
+            // - if the alarm was finished relatively recently (make sure the user has enough time to get out of bed).
+            output.color = colors.in_bed.into();
+            output.reason = if is_night {
+                SceneReason::Night
+            } else if alarm_set_soon {
+                SceneReason::AlarmSetSoon
+            } else {
+                SceneReason::AlarmPlayedRecently
+            };
+        } else if inputs.is_user_in_bed {
+            if is_evening {
+                output.color = colors.in_bed.into();
+                output.reason = SceneReason::InBed;
+            } else {
+                // If the user is in bed during daytime, set a soft light to
+                // avoid complete darkness.
+                output.color = colors.evening.into();
+                output.reason = SceneReason::InBedDaytime;
+            }
+        }
+
+        output
+    }
+
+    /// Only uses what doesn't depend on the clock: the alarm playing on the phone and the user
+    /// being in bed. Otherwise a soft light is used, since it could be any time of day.
+    fn evaluate_without_time(&mut self, inputs: &SceneInputs) -> SceneOutput {
+        let colors = &inputs.colors;
+        let mut output = SceneOutput {
+            color: colors.evening.into(),
+            reason: SceneReason::TimeUnknown,
+            event: None,
+            override_event: None,
+        };
+        if inputs.is_playing && !self.dismissed_playing {
+            // The clock still measures the elapsed time correctly, also for a snooze
+            let now_utc = inputs.now.with_timezone(&Utc);
+            if self.wakeup_start.is_none() {
+                self.wakeup_start = Some(now_utc);
+                output.event = Some(SceneEvent::AlarmStarted);
+            }
+            self.wakeup(now_utc, colors, &mut output);
+            return output;
+        }
+        if self.wakeup_start.take().is_some() {
+            self.snoozed_until = None;
+            self.snoozed_at = None;
+            output.event = Some(SceneEvent::AlarmStopped);
+        }
+        if inputs.is_user_in_bed {
+            output.color = colors.in_bed.into();
+            output.reason = SceneReason::InBed;
+        }
+        output
+    }
+
+    /// Shows the sunrise of the running wakeup, or the snooze color while it is snoozed.
+    fn wakeup(&mut self, now_utc: DateTime<Utc>, colors: &SceneColors, output: &mut SceneOutput) {
+        if let Some(until) = self.snoozed_until {
+            if now_utc < until {
+                output.color = colors.snooze.into();
+                output.reason = SceneReason::Snooze;
+                return;
+            }
+            // Continue the sunrise where it was paused
+            if let (Some(start), Some(at)) = (&mut self.wakeup_start, self.snoozed_at) {
+                *start += until
+                    .signed_duration_since(at)
+                    .max(chrono::Duration::zero());
+            }
+            self.snoozed_until = None;
+            self.snoozed_at = None;
+        }
+        let elapsed = self.wakeup_start.map_or(chrono::Duration::zero(), |start| {
+            now_utc.signed_duration_since(start)
+        });
+        output.color = self
+            .sunrise
+            .evaluate(elapsed.num_milliseconds().max(0) as f32 / 1000.0);
+        output.reason = SceneReason::Wakeup;
+    }
+}
+
+#[cfg(test)]
+fn test_inputs(now: &str) -> SceneInputs {
+    SceneInputs {
+        now: DateTime::parse_from_rfc3339(now).unwrap(),
+        time_quality: TimeQuality::Synced,
+        alarm_state: InnerAlarmState {
+            next_alarm: Default::default(),
+            enabled: false,
+        },
+        alarm_last_played: AlarmLastPlayed {
+            last_played_time: None,
+        },
+        is_playing: false,
+        is_user_in_bed: false,
+        colors: SceneColors::default(),
+        schedule: Schedule::default(),
+        manual_override: None,
+        alarm_priority: crate::manual::DEFAULT_ALARM_PRIORITY,
+    }
+}
+
+#[test]
+fn test_scene_time_of_day() {
+    let mut engine = SceneEngine::new();
+    let day = engine.update(&test_inputs("2024-03-01T12:00:00+01:00"));
+    assert_eq!(day.reason, SceneReason::Day);
+    assert_eq!(day.color, [255.0, 255.0, 60.0, 0.0]);
+    let evening = engine.update(&test_inputs("2024-03-01T18:00:00+01:00"));
+    assert_eq!(evening.reason, SceneReason::Evening);
+    let night = engine.update(&test_inputs("2024-03-01T23:30:00+01:00"));
+    assert_eq!(night.reason, SceneReason::Night);
+    assert_eq!(night.color, [0.0; 4]);
+
+    let mut inputs = test_inputs("2024-03-01T14:00:00+01:00");
+    inputs.is_user_in_bed = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::InBedDaytime);
+    inputs.manual_override = Some(serde_json::from_str(r#"{"color": "rgb(255,0,128)"}"#).unwrap());
+    let overridden = engine.update(&inputs);
+    assert_eq!(overridden.reason, SceneReason::Override);
+    assert_eq!(overridden.color, [255.0, 0.0, 128.0, 0.0]);
+}
+
+#[test]
+fn test_scene_override_expiry() {
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("2024-03-01T16:00:00+01:00");
+    inputs.manual_override = Some(
+        serde_json::from_str(
+            r#"{"color": "rgb(255,255,255)", "source": "switch", "expires": "scene_change"}"#,
+        )
+        .unwrap(),
+    );
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+
+    // The evening scene starts, so the schedule takes over again and stays in control
+    inputs.now += chrono::Duration::hours(1);
+    let evening = engine.update(&inputs);
+    assert_eq!(evening.reason, SceneReason::Evening);
+    assert_eq!(evening.override_event, Some(OverrideEvent::Expired));
+    inputs.now += chrono::Duration::hours(10);
+    assert_eq!(engine.update(&inputs).override_event, None);
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Night);
+
+    // A new override with a fixed expiry time
+    let expiry = inputs.now + chrono::Duration::minutes(30);
+    inputs.manual_override = Some(ManualOverride {
+        expires: Some(OverrideExpiry::At(expiry.with_timezone(&Utc))),
+        ..inputs.manual_override.clone().unwrap()
+    });
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+    inputs.now = expiry;
+    assert_eq!(
+        engine.update(&inputs).override_event,
+        Some(OverrideEvent::Expired)
+    );
+
+    // An override from before the time was known stays once it is
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("2024-03-01T16:00:00+01:00");
+    inputs.time_quality = TimeQuality::Unknown;
+    inputs.manual_override = Some(
+        serde_json::from_str(
+            r#"{"color": "rgb(255,255,255)", "source": "button", "expires": "scene_change"}"#,
+        )
+        .unwrap(),
+    );
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+    inputs.time_quality = TimeQuality::Synced;
+    let synced = engine.update(&inputs);
+    assert_eq!(synced.reason, SceneReason::Override);
+    assert_eq!(synced.override_event, None);
+    // And expires with the next scene
+    inputs.now += chrono::Duration::hours(1);
+    assert_eq!(
+        engine.update(&inputs).override_event,
+        Some(OverrideEvent::Expired)
+    );
+}
+
+#[test]
+fn test_scene_override_priority() {
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("2024-03-01T07:00:00+01:00");
+    inputs.manual_override = Some(serde_json::from_str(r#"{"color": "rgb(0,0,255)"}"#).unwrap());
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+
+    // The alarm has a higher priority than the override, so the wakeup light takes over
+    inputs.is_playing = true;
+    let wakeup = engine.update(&inputs);
+    assert_eq!(wakeup.reason, SceneReason::Wakeup);
+    assert_eq!(wakeup.override_event, Some(OverrideEvent::Preempted));
+    // And the override comes back when the alarm stops
+    inputs.is_playing = false;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+
+    // A higher priority override is not replaced
+    inputs.manual_override.as_mut().unwrap().priority = 100;
+    inputs.is_playing = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Override);
+}
+
+#[test]
+fn test_scene_alarm_and_snooze() {
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("2024-03-01T07:00:00+01:00");
+    let alarm = inputs.now.with_timezone(&Utc) + chrono::Duration::seconds(10);
+    inputs.alarm_state = InnerAlarmState {
+        next_alarm: alarm,
+        enabled: true,
+    };
+
+    let start = engine.update(&inputs);
+    assert_eq!(start.reason, SceneReason::Wakeup);
+    assert_eq!(start.event, Some(SceneEvent::AlarmStarted));
+
+    inputs.now += chrono::Duration::seconds(120);
+    inputs.is_playing = true;
+    let ramp = engine.update(&inputs);
+    assert_eq!(ramp.event, None);
+    assert_eq!(ramp.color, SunriseCurve::builtin().evaluate(120.0));
+
+    // The user snoozes: the alarm stops playing but stays enabled with the same trigger time
+    inputs.now += chrono::Duration::seconds(60);
+    inputs.is_playing = false;
+    inputs.alarm_last_played.last_played_time = Some(inputs.now.with_timezone(&Utc));
+    let snooze = engine.update(&inputs);
+    assert_eq!(snooze.reason, SceneReason::Snooze);
+    assert_eq!(snooze.event, Some(SceneEvent::AlarmStopped));
+
+    // Once the alarm is disabled the light stays off until the user has had time to get up
+    inputs.alarm_state.enabled = false;
+    inputs.now = DateTime::parse_from_rfc3339("2024-03-01T11:05:00+01:00").unwrap();
+    inputs.alarm_last_played.last_played_time = Some(inputs.now.with_timezone(&Utc));
+    assert_eq!(
+        engine.update(&inputs).reason,
+        SceneReason::AlarmPlayedRecently
+    );
+}
+
+#[test]
+fn test_scene_local_snooze_and_dismiss() {
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("2024-03-01T07:00:00+01:00");
+    inputs.alarm_state = InnerAlarmState {
+        next_alarm: inputs.now.with_timezone(&Utc),
+        enabled: true,
+    };
+    inputs.is_playing = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Wakeup);
+
+    let now = inputs.now.with_timezone(&Utc);
+    engine.snooze(now, now + chrono::Duration::minutes(9));
+    inputs.now += chrono::Duration::minutes(5);
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Snooze);
+    inputs.now += chrono::Duration::minutes(5);
+    // The sunrise continues where it was paused
+    let wakeup = engine.update(&inputs);
+    assert_eq!(wakeup.reason, SceneReason::Wakeup);
+    assert_eq!(wakeup.color, SunriseCurve::builtin().evaluate(60.0));
+
+    engine.dismiss(&inputs.alarm_state);
+    let dismissed = engine.update(&inputs);
+    assert_eq!(dismissed.event, Some(SceneEvent::AlarmStopped));
+    assert_ne!(dismissed.reason, SceneReason::Wakeup);
+    assert!(!engine.is_waking_up());
+
+    // The next alarm starts the wakeup light again
+    inputs.alarm_state.next_alarm += chrono::Duration::days(1);
+    inputs.now += chrono::Duration::days(1);
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Wakeup);
+}
+
+#[test]
+fn test_scene_time_unknown() {
+    let mut engine = SceneEngine::new();
+    // A clock that was never synced, and an alarm that looks like it is due
+    let mut inputs = test_inputs("1970-01-01T00:00:10+00:00");
+    inputs.time_quality = TimeQuality::Unknown;
+    inputs.alarm_state = InnerAlarmState {
+        next_alarm: inputs.now.with_timezone(&Utc),
+        enabled: true,
+    };
+    let output = engine.update(&inputs);
+    assert_eq!(output.reason, SceneReason::TimeUnknown);
+    assert_eq!(output.color, <[f32; 4]>::from(inputs.colors.evening));
+
+    inputs.is_user_in_bed = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::InBed);
+
+    // The phone playing the alarm still starts the wakeup light
+    inputs.is_playing = true;
+    let wakeup = engine.update(&inputs);
+    assert_eq!(wakeup.reason, SceneReason::Wakeup);
+    assert_eq!(wakeup.event, Some(SceneEvent::AlarmStarted));
+    inputs.is_playing = false;
+    assert_eq!(engine.update(&inputs).event, Some(SceneEvent::AlarmStopped));
+
+    // A clock that ran on its own for a long time is good enough for the schedule, but not for
+    // starting the wakeup light before the phone plays the alarm
+    let mut inputs = test_inputs("2024-03-01T12:00:00+01:00");
+    inputs.time_quality = TimeQuality::Estimated {
+        max_error: std::time::Duration::from_secs(5 * 60),
+    };
+    inputs.alarm_state = InnerAlarmState {
+        next_alarm: inputs.now.with_timezone(&Utc),
+        enabled: true,
+    };
+    assert_eq!(engine.update(&inputs).reason, SceneReason::AlarmSetSoon);
+    inputs.alarm_state.enabled = false;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Day);
+}
+
+#[test]
+fn test_scene_time_unknown_snooze_and_dismiss() {
+    let mut engine = SceneEngine::new();
+    let mut inputs = test_inputs("1970-01-01T00:00:10+00:00");
+    inputs.time_quality = TimeQuality::Unknown;
+    inputs.is_playing = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Wakeup);
+
+    // The snooze is measured by the time that passed, even though the time itself is unknown
+    inputs.now += chrono::Duration::seconds(60);
+    let now = inputs.now.with_timezone(&Utc);
+    engine.snooze(now, now + chrono::Duration::minutes(9));
+    inputs.now += chrono::Duration::minutes(5);
+    let snoozed = engine.update(&inputs);
+    assert_eq!(snoozed.reason, SceneReason::Snooze);
+    assert_eq!(snoozed.color, <[f32; 4]>::from(inputs.colors.snooze));
+    inputs.now += chrono::Duration::minutes(5);
+    let wakeup = engine.update(&inputs);
+    assert_eq!(wakeup.reason, SceneReason::Wakeup);
+    assert_eq!(wakeup.color, SunriseCurve::builtin().evaluate(120.0));
+
+    // A dismissed wakeup light stays off while the phone keeps playing
+    engine.dismiss(&inputs.alarm_state);
+    let dismissed = engine.update(&inputs);
+    assert_eq!(dismissed.event, Some(SceneEvent::AlarmStopped));
+    assert_eq!(dismissed.reason, SceneReason::TimeUnknown);
+    assert!(!engine.is_waking_up());
+    inputs.now += chrono::Duration::minutes(1);
+    assert_eq!(engine.update(&inputs).reason, SceneReason::TimeUnknown);
+
+    // Until the alarm plays again
+    inputs.is_playing = false;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::TimeUnknown);
+    inputs.is_playing = true;
+    assert_eq!(engine.update(&inputs).reason, SceneReason::Wakeup);
+}
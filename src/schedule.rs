@@ -0,0 +1,148 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+/// The scenes a schedule can select.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Scene {
+    /// Uses the plant light color
+    Day,
+    /// Uses the evening light color
+    Evening,
+    /// Uses the in bed light color, the user is assumed to be asleep
+    Night,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct TimeWindow {
+    /// Days the window starts on. Empty means every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    /// If the end is before the start, the window continues past midnight
+    pub end: NaiveTime,
+    pub scene: Scene,
+}
+
+/// Replaces the regular windows on a specific date, e.g. a holiday.
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct ScheduleException {
+    pub date: NaiveDate,
+    pub windows: Vec<TimeWindow>,
+}
+
+/// Which scene is active at what time of the week.
+///
+/// The first matching window wins. When no window matches the scene is [`Scene::Day`].
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct Schedule {
+    pub windows: Vec<TimeWindow>,
+    #[serde(default)]
+    pub exceptions: Vec<ScheduleException>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        Self {
+            windows: vec![
+                TimeWindow {
+                    days: vec![],
+                    start: time(23),
+                    end: time(11),
+                    scene: Scene::Night,
+                },
+                TimeWindow {
+                    days: vec![],
+                    start: time(17),
+                    end: time(23),
+                    scene: Scene::Evening,
+                },
+            ],
+            exceptions: vec![],
+        }
+    }
+}
+
+impl TimeWindow {
+    fn wraps(&self) -> bool {
+        self.end <= self.start
+    }
+}
+
+impl Schedule {
+    /// Windows that start on the given date
+    fn windows_on(&self, date: NaiveDate) -> impl Iterator<Item = &TimeWindow> {
+        let exception = self.exceptions.iter().find(|e| e.date == date);
+        let (windows, check_day) = match exception {
+            Some(exception) => (&exception.windows, false),
+            None => (&self.windows, true),
+        };
+        windows
+            .iter()
+            .filter(move |w| !check_day || w.days.is_empty() || w.days.contains(&date.weekday()))
+    }
+
+    pub fn scene_at(&self, local: NaiveDateTime) -> Scene {
+        let date = local.date();
+        let time = local.time();
+
+        let today = self
+            .windows_on(date)
+            .find(|w| time >= w.start && (w.wraps() || time < w.end));
+        let from_yesterday = || {
+            date.pred_opt().and_then(|yesterday| {
+                self.windows_on(yesterday)
+                    .find(|w| w.wraps() && time < w.end)
+            })
+        };
+
+        today
+            .or_else(from_yesterday)
+            .map(|w| w.scene)
+            .unwrap_or(Scene::Day)
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let at = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+
+    let default = Schedule::default();
+    assert_eq!(default.scene_at(at("2024-03-01 10:59")), Scene::Night);
+    assert_eq!(default.scene_at(at("2024-03-01 11:00")), Scene::Day);
+    assert_eq!(default.scene_at(at("2024-03-01 17:00")), Scene::Evening);
+    assert_eq!(default.scene_at(at("2024-03-01 22:59")), Scene::Evening);
+    assert_eq!(default.scene_at(at("2024-03-01 23:00")), Scene::Night);
+
+    // Later evenings on friday and saturday, and new year's eve is a holiday
+    let schedule: Schedule = serde_json::from_str(
+        r#"{
+            "windows": [
+                {"days": ["Fri", "Sat"], "start": "19:00", "end": "01:00", "scene": "evening"},
+                {"days": ["Fri", "Sat"], "start": "01:00", "end": "12:00", "scene": "night"},
+                {"days": ["Sun", "Mon", "Tue", "Wed", "Thu"], "start": "17:00", "end": "23:00", "scene": "evening"},
+                {"days": ["Sun", "Mon", "Tue", "Wed", "Thu"], "start": "23:00", "end": "11:00", "scene": "night"}
+            ],
+            "exceptions": [
+                {"date": "2024-12-31", "windows": [
+                    {"start": "15:00", "end": "03:00", "scene": "evening"}
+                ]}
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    // Friday
+    assert_eq!(schedule.scene_at(at("2024-03-01 18:00")), Scene::Day);
+    assert_eq!(schedule.scene_at(at("2024-03-01 23:30")), Scene::Evening);
+    // Saturday morning, continuing from the friday evening window
+    assert_eq!(schedule.scene_at(at("2024-03-02 00:30")), Scene::Evening);
+    assert_eq!(schedule.scene_at(at("2024-03-02 11:30")), Scene::Night);
+    // Monday morning continues sunday night
+    assert_eq!(schedule.scene_at(at("2024-03-04 10:30")), Scene::Night);
+    assert_eq!(schedule.scene_at(at("2024-03-04 17:30")), Scene::Evening);
+    // Holiday
+    assert_eq!(schedule.scene_at(at("2024-12-31 16:00")), Scene::Evening);
+    assert_eq!(schedule.scene_at(at("2025-01-01 02:00")), Scene::Evening);
+    assert_eq!(schedule.scene_at(at("2025-01-01 04:00")), Scene::Day);
+}
@@ -0,0 +1,254 @@
+use crate::calibration::{CalibrationError, OutputCalibration};
+use crate::dither::TemporalDither;
+
+/// Order in which a pixel's channels are sent over the wire.
+#[derive(
+    PartialEq, Eq, Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorOrder {
+    Rgb,
+    /// WS2812B and most RGB SK6812 strips
+    #[default]
+    Grb,
+    Rgbw,
+    /// RGBW SK6812 strips
+    Grbw,
+}
+
+impl ColorOrder {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorOrder::Rgb | ColorOrder::Grb => 3,
+            ColorOrder::Rgbw | ColorOrder::Grbw => 4,
+        }
+    }
+
+    /// Indices into an `[r, g, b, w]` pixel, in wire order
+    fn channels(self) -> &'static [usize] {
+        match self {
+            ColorOrder::Rgb => &[0, 1, 2],
+            ColorOrder::Grb => &[1, 0, 2],
+            ColorOrder::Rgbw => &[0, 1, 2, 3],
+            ColorOrder::Grbw => &[1, 0, 2, 3],
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct StripConfig {
+    pub pixels: u16,
+    #[serde(default)]
+    pub order: ColorOrder,
+    /// Calibration of the pixels, separate from the one of the PWM strips since the LEDs are
+    /// different. Neutral by default.
+    #[serde(default)]
+    pub calibration: OutputCalibration,
+}
+
+impl Default for StripConfig {
+    fn default() -> Self {
+        Self {
+            pixels: 60,
+            order: ColorOrder::Grb,
+            calibration: OutputCalibration::default(),
+        }
+    }
+}
+
+/// Which outputs the light is shown on.
+#[derive(
+    PartialEq, Eq, Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputBackend {
+    /// The three PWM strips
+    #[default]
+    Pwm,
+    /// The addressable strip
+    Ws2812,
+    Both,
+}
+
+impl OutputBackend {
+    pub fn uses_pwm(self) -> bool {
+        matches!(self, OutputBackend::Pwm | OutputBackend::Both)
+    }
+
+    pub fn uses_ws2812(self) -> bool {
+        matches!(self, OutputBackend::Ws2812 | OutputBackend::Both)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Default, serde::Serialize, serde::Deserialize, Hash)]
+pub struct OutputConfig {
+    pub backend: OutputBackend,
+    #[serde(default)]
+    pub strip: StripConfig,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum OutputConfigError {
+    #[error("the strip must have between 1 and {max} pixels")]
+    InvalidPixelCount { max: u16 },
+    #[error("invalid strip calibration: {0}")]
+    InvalidCalibration(#[from] CalibrationError),
+}
+
+impl OutputConfig {
+    /// Limited by the time it takes to write the strip, about 30us per pixel
+    pub const MAX_PIXELS: u16 = 300;
+
+    pub fn validate(&self) -> Result<(), OutputConfigError> {
+        if !(1..=Self::MAX_PIXELS).contains(&self.strip.pixels) {
+            return Err(OutputConfigError::InvalidPixelCount {
+                max: Self::MAX_PIXELS,
+            });
+        }
+        self.strip.calibration.validate()?;
+        Ok(())
+    }
+}
+
+/// An addressable LED strip which takes raw bytes, already in the strip's color order.
+pub trait PixelStrip {
+    type Error;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[cfg(feature = "esp")]
+impl PixelStrip for ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriver<'_> {
+    type Error = ws2812_esp32_rmt_driver::Ws2812Esp32RmtDriverError;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_blocking(bytes.iter().copied())
+    }
+}
+
+/// Records every frame written to it, for testing on the host.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingStrip {
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl PixelStrip for RecordingStrip {
+    type Error = std::convert::Infallible;
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.frames.push(bytes.to_vec());
+        Ok(())
+    }
+}
+
+/// Writes frames of per-pixel duties to an addressable strip.
+///
+/// Every channel of every pixel is dithered separately, so the 8 bits per channel of the strip can
+/// show levels in between when the frames are written at a fixed rate.
+pub struct Ws2812Output<S> {
+    strip: S,
+    config: StripConfig,
+    dither: Vec<[TemporalDither; 4]>,
+    buffer: Vec<u8>,
+}
+
+impl<S: PixelStrip> Ws2812Output<S> {
+    pub fn new(strip: S, config: StripConfig) -> Self {
+        let mut output = Self {
+            strip,
+            config: StripConfig::default(),
+            dither: Vec::new(),
+            buffer: Vec::new(),
+        };
+        output.set_config(config);
+        output
+    }
+
+    pub fn strip(&self) -> &S {
+        &self.strip
+    }
+
+    pub fn config(&self) -> &StripConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: StripConfig) {
+        let pixels = config.pixels as usize;
+        self.dither.resize_with(pixels, Default::default);
+        self.buffer = Vec::with_capacity(pixels * config.order.bytes_per_pixel());
+        self.config = config;
+    }
+
+    /// Writes one frame. `frame` holds the duty (0..1) of the red, green, blue and white channel of
+    /// each pixel. Pixels past the end of the frame are turned off, and the white channel is
+    /// ignored by RGB strips.
+    pub fn write_frame(&mut self, frame: &[[f32; 4]]) -> Result<(), S::Error> {
+        self.buffer.clear();
+        let channels = self.config.order.channels();
+        let off = [0.0; 4];
+        for (i, dither) in self.dither.iter_mut().enumerate() {
+            let pixel = frame.get(i).unwrap_or(&off);
+            for &c in channels {
+                let duty = pixel[c].clamp(0.0, 1.0) as f64 * 255.0;
+                self.buffer.push(dither[c].next(duty, 255) as u8);
+            }
+        }
+        self.strip.write_bytes(&self.buffer)
+    }
+
+    /// Writes the same color to every pixel
+    pub fn write_uniform(&mut self, color: [f32; 4]) -> Result<(), S::Error> {
+        let frame = vec![color; self.config.pixels as usize];
+        self.write_frame(&frame)
+    }
+}
+
+#[test]
+fn test_ws2812_output() {
+    let config: OutputConfig =
+        serde_json::from_str(r#"{"backend": "both", "strip": {"pixels": 3, "order": "grbw"}}"#)
+            .unwrap();
+    assert_eq!(config.validate(), Ok(()));
+    assert!(config.backend.uses_pwm() && config.backend.uses_ws2812());
+
+    let mut output = Ws2812Output::new(RecordingStrip::default(), config.strip);
+    output
+        .write_frame(&[[1.0, 0.5, 0.0, 0.25], [0.0, 0.0, 1.0, 0.0]])
+        .unwrap();
+    assert_eq!(
+        output.strip().frames[0],
+        [127, 255, 0, 63, 0, 0, 255, 0, 0, 0, 0, 0]
+    );
+
+    // Each channel is dithered separately, so the mean level of every pixel is exact
+    output.set_config(StripConfig {
+        pixels: 2,
+        order: ColorOrder::Grb,
+        ..Default::default()
+    });
+    for _ in 0..100 {
+        output
+            .write_frame(&[[0.3 / 255.0, 0.0, 0.0, 0.0], [0.0, 0.5 / 255.0, 0.0, 0.0]])
+            .unwrap();
+    }
+    let frames = &output.strip().frames[1..];
+    assert!(frames.iter().all(|f| f.len() == 6));
+    let sum = |byte: usize| frames.iter().map(|f| f[byte] as u32).sum::<u32>();
+    assert!(sum(1).abs_diff(30) <= 1, "{}", sum(1));
+    assert!(sum(3).abs_diff(50) <= 1, "{}", sum(3));
+
+    let invalid = OutputConfig {
+        strip: StripConfig {
+            pixels: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(invalid.validate().is_err());
+    let mut invalid = OutputConfig::default();
+    invalid.strip.calibration.max_duty[0] = 2.0;
+    assert!(matches!(
+        invalid.validate(),
+        Err(OutputConfigError::InvalidCalibration(_))
+    ));
+}
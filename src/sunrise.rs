@@ -0,0 +1,136 @@
+use crate::color::lerp;
+use crate::easing::Easing;
+
+pub const SUNRISE_ANIMATION_RGBW: &[(f32, [f32; 4])] = &[
+    (0.0f32, [0.0, 0.0, 0.0, 0.0]),
+    (1.0 * 60.0, [255.0, 70.0, 0.0, 0.0]),
+    (3.0 * 60.0, [255.0, 87.0, 0.0, 87.0]),
+    (5.0 * 60.0, [255.0, 123.0, 0.0, 123.0]),
+    (20.0 * 60.0, [255.0, 123.0, 0.0, 160.0]),
+];
+
+pub const SUNRISE_ANIMATION: &[(f32, [f32; 4])] = &[
+    (0.0, [0.0, 0.0, 0.0, 0.0]),
+    (1.0 * 60.0, [0.0, 0.0, 100.0, 0.0]),
+    (3.0 * 60.0, [0.0, 50.0, 140.0, 0.0]),
+    (5.0 * 60.0, [20.0, 100.0, 180.0, 0.0]),
+    (20.0 * 60.0, [150.0, 100.0, 200.0, 0.0]),
+];
+
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct SunriseKeyframe {
+    /// Seconds since the wakeup light started
+    pub time: u32,
+    /// Color in the range 0..255 per channel
+    pub color: [u32; 4],
+    /// Easing of the segment that ends at this keyframe
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+/// A sunrise animation that can be configured over MQTT.
+#[derive(PartialEq, Eq, Debug, Clone, serde::Serialize, serde::Deserialize, Hash)]
+pub struct SunriseCurve {
+    pub keyframes: Vec<SunriseKeyframe>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+pub enum SunriseCurveError {
+    #[error("the curve needs at least one keyframe")]
+    Empty,
+    #[error("keyframe {index} is not after the previous keyframe")]
+    NotIncreasing { index: usize },
+    #[error("channel {channel} of keyframe {index} is outside 0..255")]
+    ChannelOutOfRange { index: usize, channel: usize },
+}
+
+impl Default for SunriseCurve {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl SunriseCurve {
+    pub fn builtin() -> Self {
+        Self {
+            keyframes: SUNRISE_ANIMATION
+                .iter()
+                .map(|&(time, color)| SunriseKeyframe {
+                    time: time as u32,
+                    color: color.map(|c| c as u32),
+                    easing: Easing::Linear,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SunriseCurveError> {
+        if self.keyframes.is_empty() {
+            return Err(SunriseCurveError::Empty);
+        }
+
+        for (index, keyframe) in self.keyframes.iter().enumerate() {
+            if index > 0 && keyframe.time <= self.keyframes[index - 1].time {
+                return Err(SunriseCurveError::NotIncreasing { index });
+            }
+            if let Some(channel) = keyframe.color.iter().position(|&c| c > 255) {
+                return Err(SunriseCurveError::ChannelOutOfRange { index, channel });
+            }
+        }
+        Ok(())
+    }
+
+    /// Color at `t` seconds into the animation. The curve must be valid.
+    pub fn evaluate(&self, t: f32) -> [f32; 4] {
+        let color = |keyframe: &SunriseKeyframe| keyframe.color.map(|c| c as f32);
+        for w in self.keyframes.windows(2) {
+            let (a, b) = (&w[0], &w[1]);
+            let (at, bt) = (a.time as f32, b.time as f32);
+            if t >= at && t < bt {
+                let progress = b.easing.apply((t - at) / (bt - at));
+                return lerp(color(a), color(b), progress);
+            }
+        }
+        if t < self.keyframes[0].time as f32 {
+            color(&self.keyframes[0])
+        } else {
+            color(self.keyframes.last().unwrap())
+        }
+    }
+}
+
+#[test]
+fn test_sunrise_curve() {
+    let builtin = SunriseCurve::builtin();
+    assert_eq!(builtin.validate(), Ok(()));
+    assert_eq!(builtin.evaluate(0.0), [0.0; 4]);
+    assert_eq!(builtin.evaluate(30.0), [0.0, 0.0, 50.0, 0.0]);
+    assert_eq!(builtin.evaluate(3600.0), SUNRISE_ANIMATION[4].1);
+
+    let curve: SunriseCurve = serde_json::from_str(
+        r#"{"keyframes": [
+            {"time": 0, "color": [0, 0, 0, 0]},
+            {"time": 100, "color": [200, 0, 0, 100], "easing": "ease_in"}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(curve.validate(), Ok(()));
+    assert_eq!(curve.evaluate(50.0), [50.0, 0.0, 0.0, 25.0]);
+
+    let mut invalid = curve.clone();
+    invalid.keyframes[1].time = 0;
+    assert_eq!(
+        invalid.validate(),
+        Err(SunriseCurveError::NotIncreasing { index: 1 })
+    );
+
+    invalid = curve.clone();
+    invalid.keyframes[1].color[2] = 300;
+    assert_eq!(
+        invalid.validate(),
+        Err(SunriseCurveError::ChannelOutOfRange {
+            index: 1,
+            channel: 2
+        })
+    );
+}
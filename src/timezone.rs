@@ -0,0 +1,383 @@
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, Utc, Weekday};
+
+/// Timezone used when nothing has been configured.
+pub const DEFAULT_TIMEZONE: &str = "Europe/Stockholm";
+
+/// A small table of IANA names, so that the configuration can use a readable name
+/// instead of a POSIX TZ string. Anything not in here has to be given as a POSIX TZ string.
+const ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Copenhagen", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+];
+
+#[derive(PartialEq, Eq, Debug, Clone, thiserror::Error)]
+#[error("invalid timezone {0:?}, expected a POSIX TZ string or a known IANA name")]
+pub struct TimezoneError(pub String);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum DateRule {
+    /// `Jn`: day 1..365, February 29 is never counted
+    Julian1(u32),
+    /// `n`: day 0..365, February 29 is counted in leap years
+    Julian0(u32),
+    /// `Mm.w.d`: weekday `d` (0 = Sunday) of week `w` (5 = last) of month `m`
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct Transition {
+    date: DateRule,
+    /// Seconds after local midnight
+    time: i32,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct DaylightSaving {
+    /// Seconds east of UTC
+    offset: i32,
+    start: Transition,
+    end: Transition,
+}
+
+/// A timezone rule in the format of the POSIX `TZ` environment variable,
+/// e.g. `CET-1CEST,M3.5.0,M10.5.0/3`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Timezone {
+    /// Seconds east of UTC
+    std_offset: i32,
+    dst: Option<DaylightSaving>,
+}
+
+impl Timezone {
+    pub fn utc() -> Self {
+        Self {
+            std_offset: 0,
+            dst: None,
+        }
+    }
+
+    /// Parses either an IANA name from the embedded table or a POSIX TZ string.
+    pub fn from_config(config: &str) -> Result<Self, TimezoneError> {
+        let config = config.trim();
+        match ZONES.iter().find(|(name, _)| *name == config) {
+            Some((_, posix)) => Self::parse_posix(posix),
+            None => Self::parse_posix(config),
+        }
+    }
+
+    pub fn parse_posix(tz: &str) -> Result<Self, TimezoneError> {
+        let err = || TimezoneError(tz.to_string());
+        let mut parser = Parser { rest: tz };
+
+        parser.name().ok_or_else(err)?;
+        // Offsets of a day or more can't be represented, and are not valid POSIX anyway
+        let std_offset = -parser.offset(24).ok_or_else(err)?;
+        if parser.rest.is_empty() {
+            FixedOffset::east_opt(std_offset).ok_or_else(err)?;
+            return Ok(Self {
+                std_offset,
+                dst: None,
+            });
+        }
+
+        parser.name().ok_or_else(err)?;
+        let dst_offset = if parser.rest.is_empty() || parser.rest.starts_with(',') {
+            std_offset + 3600
+        } else {
+            -parser.offset(24).ok_or_else(err)?
+        };
+        if FixedOffset::east_opt(std_offset).is_none()
+            || FixedOffset::east_opt(dst_offset).is_none()
+        {
+            return Err(err());
+        }
+
+        let (start, end) = if parser.rest.is_empty() {
+            // Same default as glibc
+            (
+                Transition {
+                    date: DateRule::MonthWeekDay {
+                        month: 3,
+                        week: 2,
+                        weekday: 0,
+                    },
+                    time: 7200,
+                },
+                Transition {
+                    date: DateRule::MonthWeekDay {
+                        month: 11,
+                        week: 1,
+                        weekday: 0,
+                    },
+                    time: 7200,
+                },
+            )
+        } else {
+            parser.expect(',').ok_or_else(err)?;
+            let start = parser.transition().ok_or_else(err)?;
+            parser.expect(',').ok_or_else(err)?;
+            let end = parser.transition().ok_or_else(err)?;
+            (start, end)
+        };
+
+        if !parser.rest.is_empty() {
+            return Err(err());
+        }
+
+        Ok(Self {
+            std_offset,
+            dst: Some(DaylightSaving {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Offset from UTC at the given instant
+    pub fn offset_at(&self, utc: DateTime<Utc>) -> FixedOffset {
+        let offset = match &self.dst {
+            Some(dst) if self.is_dst(dst, utc.naive_utc()) => dst.offset,
+            _ => self.std_offset,
+        };
+        // Both offsets are checked to be less than a day when parsing
+        FixedOffset::east_opt(offset).unwrap_or(FixedOffset::east_opt(0).unwrap())
+    }
+
+    pub fn to_local(&self, utc: DateTime<Utc>) -> DateTime<FixedOffset> {
+        utc.with_timezone(&self.offset_at(utc))
+    }
+
+    fn is_dst(&self, dst: &DaylightSaving, utc: NaiveDateTime) -> bool {
+        let year = utc.year();
+        // Transition times are given in the local time that is in effect just before the transition
+        let start = dst.start.at(year) - Duration::seconds(self.std_offset as i64);
+        let end = dst.end.at(year) - Duration::seconds(dst.offset as i64);
+        if start < end {
+            utc >= start && utc < end
+        } else {
+            // Southern hemisphere, daylight saving time wraps around the new year
+            utc < end || utc >= start
+        }
+    }
+}
+
+impl Transition {
+    /// Local time of the transition in the given year
+    fn at(&self, year: i32) -> NaiveDateTime {
+        let date = match self.date {
+            DateRule::Julian1(day) => {
+                let leap_day = NaiveDate::from_ymd_opt(year, 2, 29).is_some() && day >= 60;
+                NaiveDate::from_yo_opt(year, day + leap_day as u32)
+            }
+            DateRule::Julian0(day) => NaiveDate::from_yo_opt(year, day + 1),
+            DateRule::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let weekday = Weekday::try_from(((weekday + 6) % 7) as u8).unwrap();
+                NaiveDate::from_weekday_of_month_opt(year, month, weekday, week as u8).or_else(
+                    || NaiveDate::from_weekday_of_month_opt(year, month, weekday, week as u8 - 1),
+                )
+            }
+        };
+        // Dates that do not exist (day 366 in a non-leap year) are clamped to the end of the year
+        let date = date.unwrap_or_else(|| NaiveDate::from_ymd_opt(year, 12, 31).unwrap());
+        date.and_hms_opt(0, 0, 0).unwrap() + Duration::seconds(self.time as i64)
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.rest = self.rest.strip_prefix(c)?;
+        Some(())
+    }
+
+    fn name(&mut self) -> Option<&str> {
+        let (name, rest) = if let Some(quoted) = self.rest.strip_prefix('<') {
+            let end = quoted.find('>')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len());
+            self.rest.split_at(end)
+        };
+        if name.len() < 3 {
+            return None;
+        }
+        self.rest = rest;
+        Some(name)
+    }
+
+    fn number(&mut self, max: u32) -> Option<u32> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let value = self.rest[..end].parse().ok().filter(|&v| v <= max)?;
+        self.rest = &self.rest[end..];
+        Some(value)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, with at most `max_hours` hours
+    fn offset(&mut self, max_hours: u32) -> Option<i32> {
+        let sign = if self.expect('-').is_some() {
+            -1
+        } else {
+            self.expect('+');
+            1
+        };
+        let mut seconds = self.number(max_hours)? * 3600;
+        if self.expect(':').is_some() {
+            seconds += self.number(59)? * 60;
+            if self.expect(':').is_some() {
+                seconds += self.number(59)?;
+            }
+        }
+        Some(sign * seconds as i32)
+    }
+
+    fn transition(&mut self) -> Option<Transition> {
+        let date = if self.expect('J').is_some() {
+            DateRule::Julian1(self.number(365).filter(|&d| d >= 1)?)
+        } else if self.expect('M').is_some() {
+            let month = self.number(12).filter(|&m| m >= 1)?;
+            self.expect('.')?;
+            let week = self.number(5).filter(|&w| w >= 1)?;
+            self.expect('.')?;
+            let weekday = self.number(6)?;
+            DateRule::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            DateRule::Julian0(self.number(365)?)
+        };
+
+        let time = if self.expect('/').is_some() {
+            // Transition times can be up to a week off to move them to another day
+            self.offset(167)?
+        } else {
+            7200
+        };
+        Some(Transition { date, time })
+    }
+}
+
+#[test]
+fn test_parse_timezone() {
+    assert_eq!(Timezone::from_config("UTC").unwrap(), Timezone::utc());
+    assert_eq!(
+        Timezone::from_config("Europe/Stockholm").unwrap(),
+        Timezone::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap()
+    );
+    assert_eq!(
+        Timezone::parse_posix("<+0530>-5:30").unwrap(),
+        Timezone {
+            std_offset: 5 * 3600 + 30 * 60,
+            dst: None
+        }
+    );
+    assert!(Timezone::from_config("Mars/Olympus_Mons").is_err());
+    assert!(Timezone::parse_posix("CET-1CEST,M13.5.0,M10.5.0").is_err());
+    assert!(Timezone::parse_posix("CET-1CEST,M3.5.0").is_err());
+    // Offsets are limited to a day, unlike transition times
+    assert!(Timezone::from_config("XXX-25").is_err());
+    assert!(Timezone::from_config("XXX-24").is_err());
+    assert!(Timezone::from_config("XXX-23:30YYY").is_err());
+    assert!(Timezone::parse_posix("CET-1CEST,M3.5.0/-25,M10.5.0/100").is_ok());
+
+    let sydney = Timezone::from_config("Australia/Sydney").unwrap();
+    let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    assert_eq!(
+        sydney
+            .offset_at(utc("2024-01-15T00:00:00Z"))
+            .local_minus_utc(),
+        11 * 3600
+    );
+    assert_eq!(
+        sydney
+            .offset_at(utc("2024-07-15T00:00:00Z"))
+            .local_minus_utc(),
+        10 * 3600
+    );
+}
+
+#[test]
+fn test_daylight_saving_scene_decisions() {
+    use crate::clock::TimeQuality;
+    use crate::scene::{
+        AlarmLastPlayed, InnerAlarmState, SceneColors, SceneEngine, SceneInputs, SceneReason,
+    };
+
+    let tz = Timezone::from_config(DEFAULT_TIMEZONE).unwrap();
+    let reason_at = |utc: &str| {
+        let utc = DateTime::parse_from_rfc3339(utc)
+            .unwrap()
+            .with_timezone(&Utc);
+        let inputs = SceneInputs {
+            now: tz.to_local(utc),
+            time_quality: TimeQuality::Synced,
+            alarm_state: InnerAlarmState {
+                next_alarm: Default::default(),
+                enabled: false,
+            },
+            alarm_last_played: AlarmLastPlayed {
+                last_played_time: None,
+            },
+            is_playing: false,
+            is_user_in_bed: false,
+            colors: SceneColors::default(),
+            schedule: Default::default(),
+            manual_override: None,
+            alarm_priority: crate::manual::DEFAULT_ALARM_PRIORITY,
+        };
+        SceneEngine::new().update(&inputs).reason
+    };
+
+    // Spring forward: 2024-03-31 02:00 CET -> 03:00 CEST
+    assert_eq!(reason_at("2024-03-31T00:59:59Z"), SceneReason::Night);
+    assert_eq!(
+        tz.to_local("2024-03-31T01:00:00Z".parse().unwrap())
+            .to_rfc3339(),
+        "2024-03-31T03:00:00+02:00"
+    );
+    assert_eq!(reason_at("2024-03-30T15:30:00Z"), SceneReason::Day);
+    assert_eq!(reason_at("2024-03-31T15:30:00Z"), SceneReason::Evening);
+    assert_eq!(reason_at("2024-03-31T09:30:00Z"), SceneReason::Day);
+    assert_eq!(reason_at("2024-03-31T21:30:00Z"), SceneReason::Night);
+
+    // Fall back: 2024-10-27 03:00 CEST -> 02:00 CET
+    assert_eq!(
+        tz.to_local("2024-10-27T01:00:00Z".parse().unwrap())
+            .to_rfc3339(),
+        "2024-10-27T02:00:00+01:00"
+    );
+    assert_eq!(reason_at("2024-10-26T09:30:00Z"), SceneReason::Day);
+    assert_eq!(reason_at("2024-10-27T09:30:00Z"), SceneReason::Night);
+    assert_eq!(reason_at("2024-10-27T15:30:00Z"), SceneReason::Day);
+    assert_eq!(reason_at("2024-10-27T16:30:00Z"), SceneReason::Evening);
+    assert_eq!(reason_at("2024-10-27T21:30:00Z"), SceneReason::Evening);
+    assert_eq!(reason_at("2024-10-27T22:30:00Z"), SceneReason::Night);
+}
@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::easing::Easing;
+
+/// How scene changes are faded.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, serde::Serialize, serde::Deserialize, Hash)]
+pub struct TransitionConfig {
+    pub duration_ms: u32,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl Default for TransitionConfig {
+    fn default() -> Self {
+        Self {
+            duration_ms: 3000,
+            easing: Easing::EaseInOut,
+        }
+    }
+}
+
+/// Converts a color in the range 0..255 to Oklab
+fn to_oklab(c: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = c.map(|v| srgb_to_linear(v / 255.0));
+    let l = (0.41222147 * r + 0.53633254 * g + 0.05144599 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171884 * g + 0.6299787 * b).cbrt();
+    [
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    ]
+}
+
+fn from_oklab(c: [f32; 3]) -> [f32; 3] {
+    let [l, a, b] = c;
+    let l_ = l + 0.39633778 * a + 0.21580376 * b;
+    let m_ = l - 0.105561346 * a - 0.06385417 * b;
+    let s_ = l - 0.08948418 * a - 1.2914855 * b;
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+    [
+        4.0767417 * l - 3.3077116 * m + 0.23096994 * s,
+        -1.268438 * l + 2.6097574 * m - 0.34131938 * s,
+        -0.0041960863 * l - 0.7034186 * m + 1.7076147 * s,
+    ]
+    .map(|v| (linear_to_srgb(v.max(0.0)) * 255.0).clamp(0.0, 255.0))
+}
+
+/// Mixes two colors (0..255 per channel) in a perceptually uniform way.
+///
+/// The RGB part is interpolated in Oklab, the white channel is interpolated directly.
+pub fn mix_perceptual(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    if t <= 0.0 {
+        return a;
+    } else if t >= 1.0 {
+        return b;
+    }
+    let la = to_oklab([a[0], a[1], a[2]]);
+    let lb = to_oklab([b[0], b[1], b[2]]);
+    let mut mixed = [0.0; 3];
+    for i in 0..3 {
+        mixed[i] = la[i] + (lb[i] - la[i]) * t;
+    }
+    let [r, g, b_] = from_oklab(mixed);
+    [r, g, b_, a[3] + (b[3] - a[3]) * t]
+}
+
+/// Crossfades between scene colors.
+///
+/// Starting a new fade while one is in progress continues from the currently displayed color.
+#[derive(Debug, Clone)]
+pub struct Crossfade {
+    from: [f32; 4],
+    target: [f32; 4],
+    start: Instant,
+    config: TransitionConfig,
+}
+
+impl Crossfade {
+    pub fn new(color: [f32; 4], now: Instant) -> Self {
+        Self {
+            from: color,
+            target: color,
+            start: now,
+            config: TransitionConfig {
+                duration_ms: 0,
+                easing: Easing::Linear,
+            },
+        }
+    }
+
+    /// Begins fading from the current color to `target`.
+    pub fn start(&mut self, target: [f32; 4], config: TransitionConfig, now: Instant) {
+        self.from = self.color(now);
+        self.target = target;
+        self.start = now;
+        self.config = config;
+    }
+
+    /// Moves the end point of the fade without restarting it.
+    ///
+    /// Used when the target changes continuously within a scene, like the sunrise animation.
+    pub fn retarget(&mut self, target: [f32; 4]) {
+        self.target = target;
+    }
+
+    pub fn is_fading(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start)
+            < Duration::from_millis(self.config.duration_ms as u64)
+    }
+
+    pub fn color(&self, now: Instant) -> [f32; 4] {
+        if !self.is_fading(now) {
+            return self.target;
+        }
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f32();
+        let t = elapsed / (self.config.duration_ms as f32 / 1000.0);
+        mix_perceptual(self.from, self.target, self.config.easing.apply(t))
+    }
+}
+
+#[test]
+fn test_crossfade() {
+    let config = TransitionConfig {
+        duration_ms: 1000,
+        easing: Easing::Linear,
+    };
+    let plant = [255.0, 255.0, 60.0, 0.0];
+    let evening = [20.0, 128.0, 160.0, 40.0];
+    let t0 = Instant::now();
+    let ms = |v| t0 + Duration::from_millis(v);
+
+    let mut fade = Crossfade::new(plant, t0);
+    assert_eq!(fade.color(t0), plant);
+    fade.start(evening, config, t0);
+    let mid = fade.color(ms(500));
+    assert!(fade.is_fading(ms(500)));
+    assert_eq!(fade.color(ms(1000)), evening);
+    assert!(!fade.is_fading(ms(1000)));
+    assert_eq!(mid[3], 20.0);
+    // Perceptual mixing is brighter than mixing the raw values
+    assert!(mid[1] > (plant[1] + evening[1]) / 2.0);
+
+    // Interrupting continues from the displayed color
+    fade.start(plant, config, ms(500));
+    assert_eq!(fade.color(ms(500)), mid);
+    assert_eq!(fade.color(ms(1500)), plant);
+
+    for c in [plant, evening, [0.0; 4], [255.0; 4]] {
+        let round_trip = mix_perceptual(c, c, 0.5);
+        for i in 0..4 {
+            assert!((round_trip[i] - c[i]).abs() < 0.1, "{c:?} {round_trip:?}");
+        }
+    }
+}
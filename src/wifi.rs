@@ -1,38 +1,91 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
+use bedroom_lights3::connection::{
+    Backoff, BackoffConfig, DisconnectReason, WifiReason, WifiStatus,
+};
+use bedroom_lights3::device_config::{DeviceConfig, StaticIp, WifiNetwork};
+use bedroom_lights3::roaming::{
+    format_bssid, Candidate, NetworkSelector, RoamingConfig, SeenAccessPoint,
+};
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
     hal::modem::Modem,
+    handle::RawHandle,
+    ipv4,
+    netif::{EspNetif, NetifConfiguration},
     nvs::EspDefaultNvsPartition,
-    sys::{EspError, ESP_ERR_TIMEOUT},
+    sys::{esp, esp_netif_set_hostname, EspError, ESP_ERR_TIMEOUT},
     timer::EspTaskTimerService,
-    wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi},
+    wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi, WifiEvent},
 };
-use log::info;
+use log::{error, info, warn};
+use tokio::sync::watch;
+
+use crate::provisioning::run_portal;
+
+/// Failed connection attempts in a row at boot before the setup portal is started instead
+const MAX_INITIAL_CONNECT_ATTEMPTS: u32 = 10;
 
-const SSID: &str = "Octagon 2.4";
-const PASSWORD: &str = "qwertydvorak";
-const WOKWI_SSID: &str = "Wokwi-GUEST";
+/// How long the setup portal runs before the configured networks are tried again
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
+/// Connects to the configured networks and keeps the connection up.
+///
+/// Without a configuration this runs the setup portal instead, and restarts once settings have
+/// been saved. If no network can be reached at boot, the portal is offered for a while in
+/// between the attempts, so the device connects by itself once a network is back. So this only
+/// returns when connected.
 pub async fn start_wifi(
     modem: Modem,
     sys_loop: EspSystemEventLoop,
     nvs: EspDefaultNvsPartition,
     timer_service: EspTaskTimerService,
+    config: Option<&DeviceConfig>,
     is_wokwi_simulator: bool,
+    status: watch::Sender<WifiStatus>,
 ) -> [u8; 6] {
-    let wifi = AsyncWifi::wrap(
-        EspWifi::new(modem, sys_loop.clone(), Some(nvs)).unwrap(),
-        sys_loop,
+    let mut wifi = AsyncWifi::wrap(
+        EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone())).unwrap(),
+        sys_loop.clone(),
         timer_service,
     )
     .unwrap();
 
     let mac = wifi.wifi().ap_netif().get_mac().unwrap();
 
-    let mut wifi_loop = WifiLoop { wifi };
-    wifi_loop.configure(is_wokwi_simulator).await.unwrap();
-    wifi_loop.initial_connect().await.unwrap();
+    let Some(config) = config else {
+        portal_and_restart(&mut wifi, nvs).await
+    };
+    let hostname = config.hostname(&mac);
+    let mut wifi_loop = WifiLoop::new(
+        wifi,
+        &sys_loop,
+        config,
+        hostname,
+        is_wokwi_simulator,
+        status,
+    )
+    .unwrap();
+    wifi_loop.start().await.unwrap();
+    while let Err(e) = wifi_loop.initial_connect().await {
+        error!("Failed to connect to Wi-Fi: {e}, starting the setup portal for {PORTAL_TIMEOUT:?}");
+        match run_portal(
+            &mut wifi_loop.wifi,
+            nvs.clone(),
+            Some(config),
+            Some(PORTAL_TIMEOUT),
+        )
+        .await
+        {
+            Ok(true) => restart(),
+            Ok(false) => {}
+            Err(e) => error!("Failed to run the setup portal: {e}"),
+        }
+        wifi_loop.start_station().await.unwrap();
+    }
 
     tokio::spawn(async move {
         wifi_loop.stay_connected().await.unwrap();
@@ -41,92 +94,345 @@ pub async fn start_wifi(
     mac
 }
 
+async fn portal_and_restart(wifi: &mut AsyncWifi<EspWifi<'_>>, nvs: EspDefaultNvsPartition) -> ! {
+    if let Err(e) = run_portal(wifi, nvs, None, None).await {
+        error!("Failed to run the setup portal: {e}");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+    restart()
+}
+
+fn restart() -> ! {
+    info!("Restarting...");
+    esp_idf_svc::hal::reset::restart();
+}
+
 pub struct WifiLoop<'a> {
     pub wifi: AsyncWifi<EspWifi<'a>>,
+    selector: NetworkSelector,
+    /// `None` to stay with an access point for as long as the connection lasts
+    roaming: Option<RoamingConfig>,
+    /// Name of the station, sent to the DHCP server
+    hostname: String,
+    /// `None` to use DHCP
+    static_ip: Option<StaticIp>,
+    is_wokwi_simulator: bool,
+    /// Published to the rest of the firmware
+    status: watch::Sender<WifiStatus>,
+    backoff: Backoff,
+    /// Network index and BSSID of the current connection
+    current: Option<(usize, [u8; 6])>,
+    /// 802.11 reason of the last disconnect reported by the driver, 0 for none
+    last_disconnect: Arc<AtomicU16>,
+    _disconnect_events: EspSubscription<'static, System>,
 }
 
 impl<'a> WifiLoop<'a> {
-    pub async fn configure(&mut self, is_wokwi_simulator: bool) -> Result<(), EspError> {
-        let wifi_configuration = if is_wokwi_simulator {
-            ClientConfiguration {
-                ssid: WOKWI_SSID.try_into().unwrap(),
-                bssid: None,
-                auth_method: AuthMethod::None,
-                password: "".try_into().unwrap(),
-                channel: Some(6),
-                ..Default::default()
-            }
-        } else {
-            ClientConfiguration {
-                ssid: SSID.try_into().unwrap(),
-                bssid: None,
-                auth_method: AuthMethod::WPA2Personal,
-                password: PASSWORD.try_into().unwrap(),
-                channel: None,
-                ..Default::default()
+    pub fn new(
+        wifi: AsyncWifi<EspWifi<'a>>,
+        sys_loop: &EspSystemEventLoop,
+        config: &DeviceConfig,
+        hostname: String,
+        is_wokwi_simulator: bool,
+        status: watch::Sender<WifiStatus>,
+    ) -> Result<Self, EspError> {
+        let last_disconnect = Arc::new(AtomicU16::new(0));
+        let disconnect_events = sys_loop.subscribe::<WifiEvent, _>({
+            let last_disconnect = last_disconnect.clone();
+            move |event| {
+                if let WifiEvent::StaDisconnected(disconnected) = event {
+                    last_disconnect.store(disconnected.reason(), Ordering::Relaxed);
+                }
             }
+        })?;
+        Ok(Self {
+            wifi,
+            selector: NetworkSelector::new(config.wifi_networks.clone()),
+            roaming: config.wifi_roaming.then(RoamingConfig::default),
+            hostname,
+            static_ip: config.static_ip.clone(),
+            is_wokwi_simulator,
+            status,
+            backoff: Backoff::new(BackoffConfig::default()),
+            current: None,
+            last_disconnect,
+            _disconnect_events: disconnect_events,
+        })
+    }
+
+    /// The reason of the last disconnect since this was last called
+    fn take_disconnect_reason(&self) -> Option<WifiReason> {
+        match self.last_disconnect.swap(0, Ordering::Relaxed) {
+            0 => None,
+            reason => Some(WifiReason(reason)),
+        }
+    }
+
+    fn client_configuration(
+        &self,
+        network: &WifiNetwork,
+        candidate: Option<&Candidate>,
+    ) -> ClientConfiguration {
+        // The lengths are checked by DeviceConfig::validate
+        ClientConfiguration {
+            ssid: network.ssid.as_str().try_into().unwrap(),
+            bssid: candidate.and_then(|c| c.bssid),
+            auth_method: if network.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            password: network.password.as_str().try_into().unwrap(),
+            // The simulated access point is always on channel 6, which makes connecting faster
+            channel: match self.is_wokwi_simulator {
+                true => Some(6),
+                false => candidate.and_then(|c| c.channel),
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the default station interface with one that has the hostname and, if set, the
+    /// static address.
+    fn configure_netif(&mut self) -> Result<(), EspError> {
+        // The hostname is checked by DeviceConfig::validate, or it is the default
+        let hostname = self.hostname.as_str().try_into().unwrap();
+        let ip_configuration = match &self.static_ip {
+            None => ipv4::ClientConfiguration::DHCP(ipv4::DHCPClientSettings {
+                hostname: Some(hostname),
+            }),
+            Some(static_ip) => ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                ip: static_ip.ip,
+                subnet: ipv4::Subnet {
+                    gateway: static_ip.gateway,
+                    mask: ipv4::Mask(static_ip.prefix_len),
+                },
+                dns: static_ip.dns.or(Some(static_ip.gateway)),
+                secondary_dns: static_ip.secondary_dns,
+            }),
         };
-        log::info!("Connecting to {}...", wifi_configuration.ssid);
+        let netif = EspNetif::new_with_conf(&NetifConfiguration {
+            ip_configuration: Some(ipv4::Configuration::Client(ip_configuration)),
+            ..NetifConfiguration::wifi_default_client()
+        })?;
+        if self.static_ip.is_some() {
+            // Only taken from the DHCP settings, but still useful without DHCP, e.g. for mDNS
+            let hostname = CString::new(self.hostname.as_str()).unwrap();
+            // Safety: the interface is valid and the hostname is copied
+            esp!(unsafe { esp_netif_set_hostname(netif.handle(), hostname.as_ptr()) })?;
+        }
+        self.wifi.wifi_mut().swap_netif_sta(netif)?;
+
+        match &self.static_ip {
+            Some(static_ip) => info!(
+                "Station {} uses the static address {}/{} via {}",
+                self.hostname, static_ip.ip, static_ip.prefix_len, static_ip.gateway
+            ),
+            None => info!("Station {} uses DHCP", self.hostname),
+        }
+        Ok(())
+    }
 
+    /// Sets up the station interface and starts the driver, which is needed for scanning.
+    pub async fn start(&mut self) -> Result<(), EspError> {
+        self.configure_netif()?;
+        self.start_station().await
+    }
+
+    /// Starts the driver as a station, also after the setup portal used it as an access point.
+    pub async fn start_station(&mut self) -> Result<(), EspError> {
+        // Each round of attempts starts with short delays again
+        self.backoff.reset();
+        if self.wifi.is_started()? {
+            self.wifi.stop().await?;
+        }
+        let configuration = self.client_configuration(&self.selector.networks()[0], None);
         self.wifi
-            .set_configuration(&Configuration::Client(wifi_configuration))?;
+            .set_configuration(&Configuration::Client(configuration))?;
 
         info!("Starting Wi-Fi driver...");
         self.wifi.start().await
     }
 
+    /// Connects once, giving up after [`MAX_INITIAL_CONNECT_ATTEMPTS`] failed attempts.
     pub async fn initial_connect(&mut self) -> Result<(), EspError> {
-        self.do_connect_loop(true).await
+        self.do_connect_loop(Some(MAX_INITIAL_CONNECT_ATTEMPTS))
+            .await
     }
 
     pub async fn stay_connected(mut self) -> Result<(), EspError> {
-        self.do_connect_loop(false).await
+        self.do_connect_loop(None).await
+    }
+
+    /// Access points in range. A failed scan gives none, the known networks are then tried
+    /// without knowing which are in range.
+    async fn scan(&mut self) -> Vec<SeenAccessPoint> {
+        match self.wifi.scan().await {
+            Ok(access_points) => access_points
+                .into_iter()
+                .map(|ap| SeenAccessPoint {
+                    ssid: ap.ssid.to_string(),
+                    bssid: ap.bssid,
+                    channel: ap.channel,
+                    rssi: ap.signal_strength,
+                })
+                .collect(),
+            Err(e) => {
+                warn!("Wi-Fi scan failed: {e}");
+                Vec::new()
+            }
+        }
     }
 
-    async fn try_connect(wifi: &mut AsyncWifi<EspWifi<'a>>) -> Result<(), EspError> {
-        wifi.connect().await?;
+    async fn try_connect(&mut self, candidate: &Candidate) -> Result<(), EspError> {
+        let network = &self.selector.networks()[candidate.network];
+        info!(
+            "Connecting to {} ({}, {} dBm)...",
+            network.ssid,
+            candidate
+                .bssid
+                .map_or("any access point".to_string(), |b| format_bssid(&b)),
+            candidate.rssi.map_or("?".to_string(), |r| r.to_string())
+        );
+        // Forget the disconnects from before, e.g. when switching access points
+        self.take_disconnect_reason();
+        self.status.send_modify(|s| s.connecting(&network.ssid));
+        let configuration = self.client_configuration(network, Some(candidate));
+        self.wifi
+            .set_configuration(&Configuration::Client(configuration))?;
+        self.wifi.connect().await?;
+        let ssid = &self.selector.networks()[candidate.network].ssid;
+        self.status.send_modify(|s| s.associated(ssid));
 
         info!("Waiting for association...");
-        wifi.ip_wait_while(
-            |wifi| wifi.is_up().map(|s| !s),
-            Some(Duration::from_millis(5000)),
-        )
-        .await?;
+        self.wifi
+            .ip_wait_while(
+                |wifi| wifi.is_up().map(|s| !s),
+                Some(Duration::from_millis(5000)),
+            )
+            .await?;
 
         Ok(())
     }
 
-    async fn do_connect_loop(&mut self, exit_after_first_connect: bool) -> Result<(), EspError> {
-        let wifi = &mut self.wifi;
+    fn connected(&mut self, candidate: &Candidate) -> Result<(), EspError> {
+        let (bssid, rssi) = match self.wifi.wifi_mut().driver_mut().get_ap_info() {
+            Ok(info) => (info.bssid, Some(info.signal_strength)),
+            Err(_) => (candidate.bssid.unwrap_or_default(), candidate.rssi),
+        };
+        let ip = self.wifi.wifi().sta_netif().get_ip_info()?.ip;
+        self.current = Some((candidate.network, bssid));
+        self.selector.connected(candidate.network);
+        self.backoff.reset();
+        let ssid = &self.selector.networks()[candidate.network].ssid;
+        self.status.send_modify(|s| s.got_ip(ssid, bssid, rssi, ip));
+        info!("{}", *self.status.borrow());
+        Ok(())
+    }
+
+    fn failed(&mut self, candidate: &Candidate, e: &EspError) {
+        let reason = match e.code() {
+            ESP_ERR_TIMEOUT => DisconnectReason::Timeout,
+            code => DisconnectReason::ConnectFailed(code),
+        };
+        let wifi_reason = self.take_disconnect_reason();
+        self.selector.failed(candidate.network);
+        self.status.send_modify(|s| s.failed(reason, wifi_reason));
+    }
+
+    /// Moves to a clearly stronger access point of a known network if the signal is weak.
+    async fn roam_if_better(&mut self, roaming: &RoamingConfig) -> Result<(), EspError> {
+        let Some((_, bssid)) = self.current else {
+            return Ok(());
+        };
+        let rssi = self.wifi.wifi().get_rssi()?.clamp(-128, 0) as i8;
+        // Scanning pauses the connection, so only look around when the signal is weak
+        if rssi >= roaming.weak_rssi {
+            return Ok(());
+        }
+        let scan = self.scan().await;
+        let Some(candidate) = self.selector.roam(roaming, bssid, rssi, &scan) else {
+            return Ok(());
+        };
+        info!("Signal is weak ({rssi} dBm), switching access point");
+        self.current = None;
+        self.status
+            .send_modify(|s| s.disconnected(DisconnectReason::Roaming, None));
+        self.wifi.disconnect().await?;
+        match self.try_connect(&candidate).await {
+            // The connect loop tries again
+            Err(e) => {
+                self.failed(&candidate, &e);
+                Ok(())
+            }
+            Ok(()) => self.connected(&candidate),
+        }
+    }
+
+    /// Keeps reconnecting. With `max_attempts`, returns after the first connect instead, or
+    /// with the last error once that many attempts in a row have failed.
+    async fn do_connect_loop(&mut self, max_attempts: Option<u32>) -> Result<(), EspError> {
+        let mut failed_attempts = 0;
         loop {
-            // Wait for disconnect before trying to connect again.  This loop ensures
-            // we stay connected and is commonly missing from trivial examples as it's
-            // way too difficult to showcase the core logic of an example and have
-            // a proper Wi-Fi event loop without a robust async runtime.  Fortunately, we can do it
-            // now!
-            wifi.wifi_wait(|wifi| wifi.is_up(), None).await?;
-
-            info!("Connecting to Wi-Fi...");
-            if let Err(e) = Self::try_connect(wifi).await {
-                match e.code() {
-                    ESP_ERR_TIMEOUT => {
-                        log::error!(
-                            "Timeout when connecting to wifi. Trying again in a few seconds..."
-                        );
-                    }
-                    _ => {
-                        log::error!("Other error when connecting to wifi. Trying again in a few seconds: {e}");
+            if self.wifi.is_up()? {
+                // Wait for disconnect before trying to connect again, looking for a better
+                // access point every now and then meanwhile
+                match self.roaming {
+                    Some(roaming) => {
+                        match self
+                            .wifi
+                            .wifi_wait(|wifi| wifi.is_up(), Some(roaming.check_interval))
+                            .await
+                        {
+                            Ok(()) => {}
+                            Err(e) if e.code() == ESP_ERR_TIMEOUT => {
+                                if let Err(e) = self.roam_if_better(&roaming).await {
+                                    warn!("Failed to switch access point: {e}");
+                                }
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        }
                     }
+                    None => self.wifi.wifi_wait(|wifi| wifi.is_up(), None).await?,
                 }
+                let wifi_reason = self.take_disconnect_reason();
+                warn!(
+                    "Wi-Fi disconnected: {}",
+                    wifi_reason.map_or("unknown reason".to_string(), |r| r.to_string())
+                );
+                self.current = None;
+                self.status
+                    .send_modify(|s| s.disconnected(DisconnectReason::LinkLost, wifi_reason));
+            }
 
-                // Sleep for a few seconds
-                tokio::time::sleep(Duration::from_millis(2000)).await;
+            let scan = self.scan().await;
+            // There is always at least one network, see DeviceConfig::validate
+            let candidate = self.selector.pick(&scan).unwrap();
+            if let Err(e) = self.try_connect(&candidate).await {
+                self.failed(&candidate, &e);
+                failed_attempts += 1;
+                if max_attempts.is_some_and(|max| failed_attempts >= max) {
+                    return Err(e);
+                }
+                let delay = self.backoff.next_delay(random());
+                log::error!("{}, trying again in {delay:?}", *self.status.borrow());
+                tokio::time::sleep(delay).await;
                 continue;
             }
 
-            if exit_after_first_connect {
+            failed_attempts = 0;
+            self.connected(&candidate)?;
+            if max_attempts.is_some() {
                 return Ok(());
             }
         }
     }
 }
+
+/// Uniformly distributed in `0..1`, from the hardware random number generator
+fn random() -> f64 {
+    // Safety: has no preconditions
+    let random = unsafe { esp_idf_svc::sys::esp_random() };
+    random as f64 / (u32::MAX as f64 + 1.0)
+}